@@ -1,30 +1,225 @@
-use crate::chunk::AST;
-use anyhow::Result;
-// use docx_rust::document::Paragraph;
-use docx_rust::{Docx, DocxFile};
-use std::path::PathBuf;
-
-pub struct DocxAST<'a> {
-    ast: Docx<'a>,
+use crate::chunk::ooxml::{DRAWING_ML, Dialect, Package, PackageAST, WORDPROCESSING_ML, XmlPart};
+use crate::chunk::{AST, TaskType, Tasks};
+use crate::error::{Result, TrenError};
+use std::path::Path;
+
+const DOCUMENT: &str = "word/document.xml";
+
+/// Parts translated on their own besides the body, in this order; their names
+/// are matched by prefix and suffix.
+const SIDE_PARTS: [(&str, &str, &Dialect); 6] = [
+    ("word/header", ".xml", &WORDPROCESSING_ML),
+    ("word/footer", ".xml", &WORDPROCESSING_ML),
+    ("word/footnotes", ".xml", &WORDPROCESSING_ML),
+    ("word/endnotes", ".xml", &WORDPROCESSING_ML),
+    ("word/comments", ".xml", &WORDPROCESSING_ML),
+    // SmartArt text
+    ("word/diagrams/data", ".xml", &DRAWING_ML),
+];
+
+/// Parts that repeat text of the parts above: the drawing Word shows for a
+/// SmartArt diagram until it is redrawn from its data. They take the same
+/// translations instead of being translated twice.
+const MIRRORED_PARTS: [(&str, &str, &Dialect); 1] =
+    [("word/diagrams/drawing", ".xml", &DRAWING_ML)];
+
+/// A Word document translated in place: the text of its runs is replaced and
+/// every style, section and part of the package stays as it was.
+#[derive(Default)]
+pub struct DocxAST {
+    /// The body, then headers, footers, notes and the rest as side chunks.
+    package: PackageAST,
+    /// Whether comment bodies are translated.
+    comments: bool,
+}
+
+impl DocxAST {
+    pub fn with_comments(self, comments: bool) -> Self {
+        DocxAST { comments, ..self }
+    }
 }
 
-impl AST for DocxAST<'_> {
-    fn import(&mut self, filepath: PathBuf) -> Result<()> {
-        let docx = DocxFile::from_file(filepath)?;
-        self.ast = docx.parse()?;
+impl AST for DocxAST {
+    fn import(&mut self, filepath: &Path) -> Result<()> {
+        let package = Package::read(filepath)?;
+        let document = package.part(DOCUMENT)?.ok_or(TrenError::Unsupported {
+            node: format!("no {DOCUMENT}"),
+            location: filepath.display().to_string(),
+        })?;
+        let mut parts = vec![(
+            XmlPart::parse(DOCUMENT, document, &WORDPROCESSING_ML)?,
+            TaskType::Main,
+        )];
+
+        let mut names = package.names()?;
+        // header2.xml before header10.xml
+        names.sort_by_key(|name| (name.len(), name.clone()));
+        let side_parts = SIDE_PARTS.iter().map(|part| (part, false));
+        let mirrored_parts = MIRRORED_PARTS.iter().map(|part| (part, true));
+        for ((prefix, suffix, dialect), mirrored) in side_parts.chain(mirrored_parts) {
+            if *prefix == "word/comments" && !self.comments {
+                continue;
+            }
+            for name in &names {
+                // the numbered parts only: word/header1.xml but not
+                // word/footnotesExtended.xml
+                let Some(number) = name
+                    .strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_suffix(suffix))
+                else {
+                    continue;
+                };
+                if !number.chars().all(|c| c.is_ascii_digit()) {
+                    continue;
+                }
+                if let Some(xml) = package.part(name)? {
+                    let part = XmlPart::parse(name, xml, dialect)?;
+                    let part = if mirrored { part.mirrored() } else { part };
+                    parts.push((part, TaskType::Side));
+                }
+            }
+        }
+        self.package = PackageAST::new(package, parts);
         Ok(())
     }
 
-    fn export(&self, filepath: PathBuf) -> Result<()> {
-        todo!()
-        // self.ast.write_file(filepath)?;
-        // Ok(())
+    fn export(&self, filepath: &Path) -> Result<()> {
+        self.package.export(filepath)
+    }
+
+    fn to_mipcs(&self) -> Tasks {
+        self.package.to_mipcs()
     }
 
-    fn to_mipcs(&self) -> Vec<String> {
-        todo!()
+    fn apply_mipcs(&mut self, mipcs: Tasks) -> Result<()> {
+        self.package.apply_mipcs(mipcs)
     }
-    fn apply_mipcs(&mut self, mipcs: Vec<String>) -> Result<()> {
-        todo!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ooxml::tests::write_package;
+
+    const W: &str = r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main""#;
+
+    fn paragraphs(root: &str, texts: &[&str]) -> String {
+        let body = texts
+            .iter()
+            .map(|text| format!("<w:p><w:r><w:t>{text}</w:t></w:r></w:p>"))
+            .collect::<String>();
+        format!("<w:{root} {W}>{body}</w:{root}>")
+    }
+
+    fn docx() -> std::path::PathBuf {
+        write_package(
+            "in.docx",
+            &[
+                (DOCUMENT, &paragraphs("document", &["Title", "Body text"])),
+                ("word/styles.xml", "<w:styles/>"),
+                ("word/header10.xml", &paragraphs("hdr", &["Header ten"])),
+                ("word/header2.xml", &paragraphs("hdr", &["Header two"])),
+                ("word/footer1.xml", &paragraphs("ftr", &["Footer"])),
+                ("word/footnotes.xml", &paragraphs("footnotes", &["A note"])),
+                (
+                    "word/footnotesExtended.xml",
+                    &paragraphs("footnotes", &["Skipped"]),
+                ),
+                ("word/comments.xml", &paragraphs("comments", &["Fix this"])),
+            ],
+        )
+    }
+
+    #[test]
+    fn body_is_main_and_other_parts_are_sides_in_order() {
+        let mut ast = DocxAST::default();
+        ast.import(&docx()).unwrap();
+        let tasks = ast.to_mipcs();
+        assert_eq!(tasks.main, ["Title", "Body text"]);
+        assert_eq!(
+            tasks.sides,
+            ["Header two", "Header ten", "Footer", "A note"]
+        );
+
+        let mut ast = DocxAST::default().with_comments(true);
+        ast.import(&docx()).unwrap();
+        assert_eq!(ast.to_mipcs().sides.back().unwrap(), "Fix this");
+    }
+
+    #[test]
+    fn smart_art_drawings_copy_the_translated_data() {
+        const A: &str = r#"xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main""#;
+        let data = format!(
+            "<dgm:dataModel {A}><a:p><a:r><a:t>Plan</a:t></a:r></a:p><a:p><a:r><a:t>Ship it</a:t></a:r></a:p></dgm:dataModel>"
+        );
+        // the drawing splits a text into runs of its own
+        let drawing = format!(
+            "<dsp:drawing {A}><a:p><a:r><a:t>Plan</a:t></a:r></a:p><a:p><a:r><a:rPr b=\"1\"/><a:t>Ship</a:t></a:r><a:r><a:t> it</a:t></a:r></a:p></dsp:drawing>"
+        );
+        let input = write_package(
+            "smartart.docx",
+            &[
+                (DOCUMENT, &paragraphs("document", &["Body"])),
+                ("word/diagrams/data1.xml", &data),
+                ("word/diagrams/drawing1.xml", &drawing),
+            ],
+        );
+        let mut ast = DocxAST::default();
+        ast.import(&input).unwrap();
+        let tasks = ast.to_mipcs();
+        assert_eq!(tasks.sides, ["Plan", "Ship it"]);
+        ast.apply_mipcs(tasks.translated(str::to_uppercase))
+            .unwrap();
+        let output = input.with_file_name("out.docx");
+        ast.export(&output).unwrap();
+
+        let package = Package::read(&output).unwrap();
+        assert_eq!(
+            package.part("word/diagrams/drawing1.xml").unwrap().unwrap(),
+            drawing
+                .replace(">Plan<", ">PLAN<")
+                .replace(">Ship<", ">SHIP IT<")
+                .replace("> it<", "><")
+        );
+    }
+
+    #[test]
+    fn round_trip_rewrites_only_the_text() {
+        let input = docx();
+        let mut ast = DocxAST::default();
+        ast.import(&input).unwrap();
+        let tasks = ast.to_mipcs().translated(str::to_uppercase);
+        ast.apply_mipcs(tasks).unwrap();
+        let output = input.with_file_name("out.docx");
+        ast.export(&output).unwrap();
+
+        let package = Package::read(&output).unwrap();
+        assert_eq!(
+            package.part(DOCUMENT).unwrap().unwrap(),
+            paragraphs("document", &["TITLE", "BODY TEXT"])
+        );
+        assert_eq!(
+            package.part("word/footer1.xml").unwrap().unwrap(),
+            paragraphs("ftr", &["FOOTER"])
+        );
+        assert_eq!(
+            package.part("word/comments.xml").unwrap().unwrap(),
+            paragraphs("comments", &["Fix this"])
+        );
+        assert_eq!(
+            package.part("word/styles.xml").unwrap().unwrap(),
+            "<w:styles/>"
+        );
+    }
+
+    #[test]
+    fn missing_translations_are_an_error() {
+        let mut ast = DocxAST::default();
+        ast.import(&docx()).unwrap();
+        let tasks = Tasks::with_chunks(["TITLE".into()].into(), [].into());
+        assert!(matches!(
+            ast.apply_mipcs(tasks),
+            Err(TrenError::MissingChunk { index: 1, .. })
+        ));
     }
 }
@@ -1,11 +1,28 @@
-use anyhow::Result;
+use crate::cli::DocumentArgs;
+use crate::error::{Result, TrenError};
+use serde::{Deserialize, Serialize};
 // use pandoc_types::definition::{Inline, *};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::path::Path;
 
 pub const TOK_SEP: char = '𐑙';
 
+pub mod docx;
+pub mod ods;
+mod ooxml;
 pub mod pandoc_ast;
+pub mod pptx;
+pub mod spreadsheet;
+pub mod subtitle;
+pub mod xlsx;
+
+use docx::DocxAST;
+use ods::Ods;
+use pandoc_ast::PandocAST;
+use pptx::PptxAST;
+use spreadsheet::{Csv, Selection, SpreadsheetAST};
+use subtitle::{Format, SubtitleAST};
+use xlsx::Xlsx;
 
 pub trait AST {
     fn import(&mut self, filepath: &Path) -> Result<()>;
@@ -14,13 +31,93 @@ pub trait AST {
     fn export(&self, filepath: &Path) -> Result<()>;
 }
 
+/// Import `input` with the backend suited to it. Word to Word, PowerPoint to
+/// PowerPoint, spreadsheets and subtitles to the same format keep the file and
+/// only rewrite its text; everything else goes through pandoc, whose output
+/// format is checked before anything is translated.
+pub fn open_document(
+    input: &Path,
+    output: &Path,
+    document: &DocumentArgs,
+) -> Result<Box<dyn AST + Send>> {
+    let is = |kind: &str, format: &Option<String>, path: &Path| match format {
+        Some(format) => format == kind,
+        None => path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(kind)),
+    };
+    let (from, to) = (&document.from, &document.to);
+    let mut ast: Box<dyn AST + Send> = if is("docx", from, input) && is("docx", to, output) {
+        Box::new(DocxAST::default().with_comments(document.comments))
+    } else if is("pptx", from, input) {
+        if !is("pptx", to, output) {
+            return Err(TrenError::Config(format!(
+                "{} can only be translated to a .pptx presentation",
+                input.display()
+            )));
+        }
+        Box::new(PptxAST::default())
+    } else if let Some(kind) = ["xlsx", "ods", "csv", "tsv"]
+        .into_iter()
+        .find(|kind| is(kind, from, input) && is(kind, to, output))
+    {
+        let selection = Selection::new(document)?;
+        match kind {
+            "xlsx" => Box::new(SpreadsheetAST::<Xlsx>::new(selection)),
+            "ods" => Box::new(SpreadsheetAST::<Ods>::new(selection)),
+            _ => Box::new(SpreadsheetAST::<Csv>::new(selection)),
+        }
+    } else if let Some(kind) = ["xlsx", "ods"]
+        .into_iter()
+        .find(|kind| is(kind, from, input))
+    {
+        return Err(TrenError::Config(format!(
+            "{} can only be translated to a .{kind} spreadsheet",
+            input.display()
+        )));
+    } else if let Some((kind, format)) = [
+        ("srt", Format::Srt),
+        ("vtt", Format::Vtt),
+        ("ass", Format::Ass),
+        ("ssa", Format::Ass),
+    ]
+    .into_iter()
+    .find(|(kind, _)| is(kind, from, input))
+    {
+        if !is(kind, to, output) {
+            return Err(TrenError::Config(format!(
+                "{} can only be translated to a .{kind} subtitle file",
+                input.display()
+            )));
+        }
+        Box::new(
+            SubtitleAST::with_format(format).with_wrap(document.max_line_chars, document.max_lines),
+        )
+    } else {
+        let ast = PandocAST::with_formats(from.clone(), to.clone())
+            .with_meta_keys(document.meta_keys.clone());
+        ast.output_format(output)?;
+        Box::new(ast)
+    };
+    ast.import(input)?;
+    Ok(ast)
+}
+
 #[derive(Debug, Clone)]
 pub struct Tasks {
     pub main: VecDeque<String>,
     pub sides: VecDeque<String>,
+    /// Chunks shown as context for a chunk instead of the ones before it, by
+    /// type and index.
+    pub context: HashMap<(TaskType, usize), Vec<String>>,
+    /// Chunks shown as what comes after a chunk, by type and index.
+    pub next: HashMap<(TaskType, usize), Vec<String>>,
+    /// How many main and side chunks are collected so far.
+    taken: (usize, usize),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TaskType {
     Main,
     Side,
@@ -28,11 +125,19 @@ pub enum TaskType {
 
 impl Tasks {
     fn new() -> Self {
+        Tasks::with_chunks(VecDeque::new(), VecDeque::new())
+    }
+
+    pub fn with_chunks(main: VecDeque<String>, sides: VecDeque<String>) -> Self {
         Tasks {
-            main: VecDeque::new(),
-            sides: VecDeque::new(),
+            main,
+            sides,
+            context: HashMap::new(),
+            next: HashMap::new(),
+            taken: (0, 0),
         }
     }
+
     fn add(&mut self, str: String, task_type: TaskType) {
         match task_type {
             TaskType::Main => self.main.push_back(str),
@@ -40,11 +145,36 @@ impl Tasks {
         }
     }
 
-    fn collect(&mut self, task_type: TaskType) -> String {
+    /// How many chunks of a type there are.
+    fn count(&self, task_type: &TaskType) -> usize {
         match task_type {
-            TaskType::Main => self.main.pop_front(),
-            TaskType::Side => self.sides.pop_front(),
+            TaskType::Main => self.main.len(),
+            TaskType::Side => self.sides.len(),
         }
-        .expect("mismatch")
+    }
+
+    /// Next chunk of a type, with its index.
+    fn collect(&mut self, task_type: TaskType) -> Result<(usize, String)> {
+        let (chunks, taken) = match task_type {
+            TaskType::Main => (&mut self.main, &mut self.taken.0),
+            TaskType::Side => (&mut self.sides, &mut self.taken.1),
+        };
+        let index = *taken;
+        let chunk = chunks
+            .pop_front()
+            .ok_or(TrenError::MissingChunk { index, task_type })?;
+        *taken += 1;
+        Ok((index, chunk))
+    }
+}
+
+#[cfg(test)]
+impl Tasks {
+    /// Every chunk translated by `f`, as the LLM would give them back.
+    fn translated(&self, f: impl Fn(&str) -> String) -> Tasks {
+        Tasks::with_chunks(
+            self.main.iter().map(|chunk| f(chunk)).collect(),
+            self.sides.iter().map(|chunk| f(chunk)).collect(),
+        )
     }
 }
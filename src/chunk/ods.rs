@@ -0,0 +1,319 @@
+use crate::chunk::TOK_SEP;
+use crate::chunk::ooxml::{Namespaces, Package, corrupt, escape, unescape};
+use crate::chunk::spreadsheet::{Book, Cell, Sheet};
+use crate::error::{Result, TrenError};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+const CONTENT: &str = "content.xml";
+
+/// The OpenDocument namespaces the reader matches, whatever prefix the
+/// document binds them to.
+const PREFIXES: [(&str, &str); 3] = [
+    ("urn:oasis:names:tc:opendocument:xmlns:office:1.0", "office"),
+    ("urn:oasis:names:tc:opendocument:xmlns:table:1.0", "table"),
+    ("urn:oasis:names:tc:opendocument:xmlns:text:1.0", "text"),
+];
+
+/// An OpenDocument spreadsheet; only the text nodes of string cells change.
+/// A cell's chunk has `TOK_SEP` between its text nodes, which styled spans,
+/// links and line breaks separate.
+pub struct Ods {
+    package: Package,
+    xml: String,
+    /// Cells with their text nodes.
+    sheets: Vec<Sheet<Vec<Range<usize>>>>,
+}
+
+/// A `<table:table-cell>` being read.
+#[derive(Default)]
+struct RawCell {
+    column: usize,
+    repeated: usize,
+    string: bool,
+    formula: bool,
+    paragraphs: Vec<String>,
+    nodes: Vec<Range<usize>>,
+}
+
+impl Book for Ods {
+    type Key = Vec<Range<usize>>;
+
+    fn read(filepath: &Path) -> Result<Self> {
+        let package = Package::read(filepath)?;
+        let xml = package.part(CONTENT)?.ok_or(TrenError::Unsupported {
+            node: format!("no {CONTENT}"),
+            location: filepath.display().to_string(),
+        })?;
+
+        let namespaces = Namespaces::read_with(CONTENT, &xml, &PREFIXES)?;
+        let mut sheets = vec![];
+        let mut element = String::new();
+        let (mut row, mut rows_repeated, mut next_row, mut next_column) = (0, 1, 0, 0);
+        let mut cell: Option<RawCell> = None;
+        // depth of `text:p` and of annotations, whose text is not the cell's
+        let (mut paragraphs, mut annotations) = (0usize, 0);
+
+        for token in Tokenizer::from(xml.as_str()) {
+            match token.map_err(|err| corrupt(CONTENT, err))? {
+                Token::ElementStart { prefix, local, .. } => {
+                    element = namespaces.name(prefix.as_str(), local.as_str());
+                    match element.as_str() {
+                        "table:table" => {
+                            sheets.push(Sheet {
+                                name: String::new(),
+                                cells: vec![],
+                            });
+                            next_row = 0;
+                        }
+                        "table:table-row" => {
+                            row = next_row;
+                            rows_repeated = 1;
+                            next_column = 0;
+                        }
+                        "table:table-cell" | "table:covered-table-cell" => {
+                            cell = Some(RawCell {
+                                column: next_column,
+                                repeated: 1,
+                                ..Default::default()
+                            })
+                        }
+                        "text:p" | "text:h" if annotations == 0 => {
+                            paragraphs += 1;
+                            if let Some(cell) = cell.as_mut() {
+                                cell.paragraphs.push(String::new());
+                            }
+                        }
+                        "office:annotation" => annotations += 1,
+                        _ => {}
+                    }
+                }
+                Token::Attribute {
+                    prefix,
+                    local,
+                    value,
+                    ..
+                } => {
+                    let attribute = namespaces.name(prefix.as_str(), local.as_str());
+                    let number = || value.as_str().parse::<usize>().unwrap_or(1).max(1);
+                    match (element.as_str(), attribute.as_str()) {
+                        ("table:table", "table:name") => {
+                            if let Some(sheet) = sheets.last_mut() {
+                                sheet.name = unescape(value.as_str());
+                            }
+                        }
+                        ("table:table-row", "table:number-rows-repeated") => {
+                            rows_repeated = number()
+                        }
+                        (
+                            "table:table-cell" | "table:covered-table-cell",
+                            "table:number-columns-repeated",
+                        ) => {
+                            if let Some(cell) = cell.as_mut() {
+                                cell.repeated = number();
+                            }
+                        }
+                        ("table:table-cell", "office:value-type") => {
+                            if let Some(cell) = cell.as_mut() {
+                                cell.string = value.as_str() == "string";
+                            }
+                        }
+                        ("table:table-cell", "table:formula") => {
+                            if let Some(cell) = cell.as_mut() {
+                                cell.formula = true;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Token::Text { text } if paragraphs > 0 && annotations == 0 => {
+                    let Some(cell) = cell.as_mut() else {
+                        continue;
+                    };
+                    let value = unescape(text.as_str());
+                    if !value.trim().is_empty() {
+                        cell.nodes.push(text.range());
+                    }
+                    if let Some(paragraph) = cell.paragraphs.last_mut() {
+                        paragraph.push_str(&value);
+                    }
+                }
+                Token::ElementEnd { end, .. } => {
+                    let closed = match end {
+                        ElementEnd::Open => continue,
+                        ElementEnd::Empty => element.clone(),
+                        ElementEnd::Close(prefix, local) => {
+                            namespaces.name(prefix.as_str(), local.as_str())
+                        }
+                    };
+                    match closed.as_str() {
+                        "table:table-row" => next_row = row + rows_repeated,
+                        "table:table-cell" | "table:covered-table-cell" => {
+                            let Some(raw) = cell.take() else {
+                                continue;
+                            };
+                            next_column = raw.column + raw.repeated;
+                            let chunk = raw
+                                .nodes
+                                .iter()
+                                .map(|node| unescape(&xml[node.clone()]))
+                                .collect::<Vec<_>>()
+                                .join(&TOK_SEP.to_string());
+                            let translatable = raw.string && !raw.formula && !raw.nodes.is_empty();
+                            if let Some(sheet) = sheets.last_mut() {
+                                sheet.cells.push(Cell {
+                                    row,
+                                    column: raw.column,
+                                    value: raw.paragraphs.join("\n"),
+                                    text: translatable.then_some((chunk, raw.nodes)),
+                                });
+                            }
+                        }
+                        "text:p" | "text:h" if annotations == 0 => {
+                            paragraphs = paragraphs.saturating_sub(1)
+                        }
+                        "office:annotation" => annotations -= 1,
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Ods {
+            package,
+            xml,
+            sheets,
+        })
+    }
+
+    fn sheets(&self) -> &[Sheet<Self::Key>] {
+        &self.sheets
+    }
+
+    fn write(&self, filepath: &Path, translations: &[(&Self::Key, &str)]) -> Result<()> {
+        let mut edits = translations
+            .iter()
+            .flat_map(|(nodes, translation)| {
+                nodes
+                    .iter()
+                    .zip(translation.split(TOK_SEP))
+                    .map(|(node, segment)| (node.clone(), escape(segment)))
+            })
+            .collect::<Vec<_>>();
+        edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+        let mut xml = self.xml.clone();
+        for (range, text) in edits {
+            xml.replace_range(range, &text);
+        }
+        self.package
+            .write(filepath, &HashMap::from([(CONTENT.to_string(), xml)]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ooxml::tests::write_package;
+
+    const NS: &str = r#"xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" xmlns:table="urn:oasis:names:tc:opendocument:xmlns:table:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0""#;
+
+    fn ods() -> std::path::PathBuf {
+        write_package("in.ods", &[(CONTENT, &content())])
+    }
+
+    fn content() -> String {
+        format!(
+            r#"<office:document-content {NS}><office:body><office:spreadsheet><table:table table:name="Items"><table:table-row><table:table-cell table:number-columns-repeated="2"/><table:table-cell office:value-type="string"><text:p>Red <text:span>chair</text:span></text:p></table:table-cell><table:table-cell office:value-type="float" office:value="12"><text:p>12</text:p></table:table-cell></table:table-row><table:table-row table:number-rows-repeated="3"><table:table-cell table:number-columns-repeated="1024"/></table:table-row><table:table-row><table:table-cell office:value-type="string"><text:p>Note<office:annotation><text:p>hidden</text:p></office:annotation></text:p></table:table-cell><table:table-cell office:value-type="string" table:formula="of:=A5"><text:p>Note</text:p></table:table-cell></table:table-row></table:table></office:spreadsheet></office:body></office:document-content>"#
+        )
+    }
+
+    /// Binds the namespace of `from` to `to` instead.
+    fn rebind(xml: &str, from: &str, to: &str) -> String {
+        [("<", ":"), ("</", ":"), (" ", ":"), ("xmlns:", "=")]
+            .iter()
+            .fold(xml.to_string(), |xml, (before, after)| {
+                xml.replace(
+                    &format!("{before}{from}{after}"),
+                    &format!("{before}{to}{after}"),
+                )
+            })
+    }
+
+    #[test]
+    fn repeated_columns_and_rows_are_counted() {
+        let book = Ods::read(&ods()).unwrap();
+        let sheet = &book.sheets()[0];
+        assert_eq!(sheet.name, "Items");
+        let cells = sheet
+            .cells
+            .iter()
+            .map(|cell| {
+                (
+                    cell.row,
+                    cell.column,
+                    cell.value.as_str(),
+                    cell.text.as_ref().map(|(text, _)| text.as_str()),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cells,
+            [
+                (0, 0, "", None),
+                (0, 2, "Red chair", Some("Red 𐑙chair")),
+                (0, 3, "12", None),
+                (1, 0, "", None),
+                (4, 0, "Note", Some("Note")),
+                (4, 1, "Note", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_text_nodes_change() {
+        let input = ods();
+        let book = Ods::read(&input).unwrap();
+        let cells = &book.sheets()[0].cells;
+        let translations = [
+            (&cells[1].text.as_ref().unwrap().1, "Chaise 𐑙rouge & co"),
+            (&cells[4].text.as_ref().unwrap().1, "Remarque"),
+        ];
+        let output = input.with_file_name("out.ods");
+        book.write(&output, &translations).unwrap();
+
+        let xml = Package::read(&output)
+            .unwrap()
+            .part(CONTENT)
+            .unwrap()
+            .unwrap();
+        assert!(xml.contains("<text:p>Chaise <text:span>rouge &amp; co</text:span></text:p>"));
+        assert!(xml.contains("<text:p>Remarque<office:annotation><text:p>hidden</text:p>"));
+        assert!(xml.contains(r#"table:formula="of:=A5"><text:p>Note</text:p>"#));
+    }
+
+    #[test]
+    fn namespaces_are_matched_whatever_their_prefix() {
+        let content = [("office", "o"), ("table", "t"), ("text", "x")]
+            .iter()
+            .fold(content(), |xml, (from, to)| rebind(&xml, from, to));
+        assert!(content.contains("<t:table t:name=\"Items\">"));
+        let input = write_package("in.ods", &[(CONTENT, &content)]);
+        let book = Ods::read(&input).unwrap();
+        let expected = Ods::read(&ods()).unwrap();
+        let cells = |book: &Ods| {
+            book.sheets()[0]
+                .cells
+                .iter()
+                .map(|cell| {
+                    let text = cell.text.as_ref().map(|(text, _)| text.clone());
+                    (cell.row, cell.column, cell.value.clone(), text)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(book.sheets()[0].name, "Items");
+        assert_eq!(cells(&book), cells(&expected));
+    }
+}
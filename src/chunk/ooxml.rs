@@ -0,0 +1,883 @@
+//! Office Open XML packages: zip archives of XML parts whose text sits in
+//! runs. Only the text inside the runs is rewritten; every other byte, part
+//! and compression setting of the package is kept.
+
+use crate::chunk::{TOK_SEP, TaskType, Tasks};
+use crate::error::{Result, TrenError};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::ops::Range;
+use std::path::Path;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+use zip::{ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+/// Element names of one markup language.
+pub struct Dialect {
+    pub paragraph: &'static str,
+    pub run: &'static str,
+    pub run_props: &'static str,
+    pub text: &'static str,
+    /// Elements that may sit between two runs without splitting their text
+    /// into separate segments.
+    pub transparent: &'static [&'static str],
+    /// Text frames whose paragraphs make a single chunk.
+    pub frames: &'static [&'static str],
+    /// Elements whose text is generated, such as slide numbers.
+    pub skipped: &'static [&'static str],
+    /// Whether leading or trailing spaces need `xml:space="preserve"`.
+    pub space_preserve: bool,
+}
+
+pub const WORDPROCESSING_ML: Dialect = Dialect {
+    paragraph: "w:p",
+    run: "w:r",
+    run_props: "w:rPr",
+    text: "w:t",
+    transparent: &["w:proofErr", "w:lastRenderedPageBreak"],
+    frames: &[],
+    skipped: &[],
+    space_preserve: true,
+};
+
+/// Text of shapes and SmartArt.
+pub const DRAWING_ML: Dialect = Dialect {
+    paragraph: "a:p",
+    run: "a:r",
+    run_props: "a:rPr",
+    text: "a:t",
+    transparent: &[],
+    frames: &[],
+    skipped: &["a:fld"],
+    space_preserve: false,
+};
+
+/// Shared strings of a workbook, rich text included; phonetic guides are left
+/// alone.
+pub const SPREADSHEET_ML: Dialect = Dialect {
+    paragraph: "si",
+    run: "r",
+    run_props: "rPr",
+    text: "t",
+    transparent: &[],
+    frames: &[],
+    skipped: &["rPh"],
+    space_preserve: true,
+};
+
+/// A string written in the cell itself rather than shared.
+pub const INLINE_STRING: Dialect = Dialect {
+    paragraph: "is",
+    ..SPREADSHEET_ML
+};
+
+/// Slides: each text frame of a shape or table cell is one chunk.
+pub const PRESENTATION_ML: Dialect = Dialect {
+    frames: &["p:txBody", "a:txBody"],
+    ..DRAWING_ML
+};
+
+/// The prefix dialects name the elements of a namespace with, whatever prefix
+/// a part binds it to: SpreadsheetML written as `<x:sheetData>` or a default
+/// WordprocessingML namespace still matches. Strict documents use the
+/// `purl.oclc.org` namespaces.
+const PREFIXES: [(&str, &str); 9] = [
+    (
+        "http://schemas.openxmlformats.org/wordprocessingml/2006/main",
+        "w",
+    ),
+    ("http://purl.oclc.org/ooxml/wordprocessingml/main", "w"),
+    ("http://schemas.openxmlformats.org/drawingml/2006/main", "a"),
+    ("http://purl.oclc.org/ooxml/drawingml/main", "a"),
+    (
+        "http://schemas.openxmlformats.org/presentationml/2006/main",
+        "p",
+    ),
+    ("http://purl.oclc.org/ooxml/presentationml/main", "p"),
+    (
+        "http://schemas.openxmlformats.org/spreadsheetml/2006/main",
+        "",
+    ),
+    ("http://purl.oclc.org/ooxml/spreadsheetml/main", ""),
+    (
+        "http://schemas.openxmlformats.org/markup-compatibility/2006",
+        "mc",
+    ),
+];
+
+/// The namespace prefixes bound in a part, mapped to the ones the dialects
+/// use. Prefixes of other namespaces are kept as they are.
+pub struct Namespaces(HashMap<String, &'static str>);
+
+impl Namespaces {
+    pub fn read(location: &str, xml: &str) -> Result<Self> {
+        Self::read_with(location, xml, &PREFIXES)
+    }
+
+    /// Reads the prefixes bound to the namespaces of `known`, a table of
+    /// namespace URIs and the prefixes they map to.
+    pub fn read_with(location: &str, xml: &str, known: &[(&str, &'static str)]) -> Result<Self> {
+        let mut prefixes = HashMap::new();
+        for token in Tokenizer::from(xml) {
+            if let Token::Attribute {
+                prefix,
+                local,
+                value,
+                ..
+            } = token.map_err(|err| corrupt(location, err))?
+            {
+                let bound = match (prefix.as_str(), local.as_str()) {
+                    ("xmlns", prefix) => prefix,
+                    ("", "xmlns") => "",
+                    _ => continue,
+                };
+                if let Some((_, canonical)) = known
+                    .iter()
+                    .find(|(namespace, _)| *namespace == value.as_str())
+                {
+                    prefixes.insert(bound.to_string(), *canonical);
+                }
+            }
+        }
+        Ok(Namespaces(prefixes))
+    }
+
+    /// The name of an element as the dialects spell it.
+    pub fn name(&self, prefix: &str, local: &str) -> String {
+        qualified(self.0.get(prefix).copied().unwrap_or(prefix), local)
+    }
+}
+
+/// Content for readers that do not know the `mc:Choice` of an
+/// `mc:AlternateContent`, such as the VML copy of a text box. Its paragraphs
+/// are not chunks; they get the translation of the same text in the choice.
+const FALLBACK: &str = "mc:Fallback";
+
+pub fn corrupt(location: &str, err: impl std::fmt::Display) -> TrenError {
+    TrenError::Unsupported {
+        node: err.to_string(),
+        location: location.to_string(),
+    }
+}
+
+/// The zip archive of a document, kept as read.
+#[derive(Default)]
+pub struct Package {
+    bytes: Vec<u8>,
+    location: String,
+}
+
+impl Package {
+    pub fn read(filepath: &Path) -> Result<Self> {
+        let package = Package {
+            bytes: std::fs::read(filepath)?,
+            location: filepath.display().to_string(),
+        };
+        package.archive()?;
+        Ok(package)
+    }
+
+    fn archive(&self) -> Result<ZipArchive<Cursor<&[u8]>>> {
+        ZipArchive::new(Cursor::new(self.bytes.as_slice()))
+            .map_err(|err| corrupt(&self.location, err))
+    }
+
+    /// Names of the parts, in archive order.
+    pub fn names(&self) -> Result<Vec<String>> {
+        Ok(self.archive()?.file_names().map(String::from).collect())
+    }
+
+    /// Text of an XML part, if the package has it.
+    pub fn part(&self, name: &str) -> Result<Option<String>> {
+        let mut archive = self.archive()?;
+        let mut file = match archive.by_name(name) {
+            Ok(file) => file,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(err) => return Err(corrupt(name, err)),
+        };
+        let mut xml = String::new();
+        file.read_to_string(&mut xml)
+            .map_err(|err| corrupt(name, err))?;
+        Ok(Some(xml))
+    }
+
+    /// Write the package with some parts replaced; the others are copied
+    /// without even recompressing them.
+    pub fn write(&self, filepath: &Path, replaced: &HashMap<String, String>) -> Result<()> {
+        let mut archive = self.archive()?;
+        let mut writer = ZipWriter::new(std::fs::File::create(filepath)?);
+        let bad_output = |err: zip::result::ZipError| corrupt(&filepath.display().to_string(), err);
+        for i in 0..archive.len() {
+            let file = archive
+                .by_index_raw(i)
+                .map_err(|err| corrupt(&self.location, err))?;
+            match replaced.get(file.name()) {
+                Some(xml) => {
+                    let options =
+                        SimpleFileOptions::default().compression_method(file.compression());
+                    writer
+                        .start_file(file.name(), options)
+                        .map_err(bad_output)?;
+                    std::io::Write::write_all(&mut writer, xml.as_bytes())?;
+                }
+                None => writer.raw_copy_file(file).map_err(bad_output)?,
+            }
+        }
+        writer.finish().map_err(bad_output)?;
+        Ok(())
+    }
+
+    /// The relationships of a part as (id, type, part name) triples.
+    pub fn relationships(&self, name: &str) -> Result<Vec<(String, String, String)>> {
+        let (dir, file) = name.rsplit_once('/').unwrap_or(("", name));
+        let rels = format!("{dir}/_rels/{file}.rels");
+        let Some(xml) = self.part(&rels)? else {
+            return Ok(vec![]);
+        };
+
+        let mut relationships = vec![];
+        let mut current: Option<(String, String, String, bool)> = None;
+        for token in Tokenizer::from(xml.as_str()) {
+            match token.map_err(|err| corrupt(&rels, err))? {
+                Token::ElementStart { local, .. } => {
+                    current = (local.as_str() == "Relationship").then(Default::default);
+                }
+                Token::Attribute { local, value, .. } => {
+                    if let Some((id, kind, target, external)) = current.as_mut() {
+                        match local.as_str() {
+                            "Id" => *id = value.to_string(),
+                            "Type" => *kind = value.to_string(),
+                            "Target" => *target = value.to_string(),
+                            "TargetMode" => *external = value.as_str() == "External",
+                            _ => {}
+                        }
+                    }
+                }
+                Token::ElementEnd { .. } => {
+                    if let Some((id, kind, target, external)) = current.take()
+                        && !external
+                    {
+                        relationships.push((id, kind, resolve(dir, &target)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(relationships)
+    }
+}
+
+/// A relationship target as a part name: relative to the directory of its
+/// source part, or to the package root when it starts with `/`.
+fn resolve(dir: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = match target.strip_prefix('/') {
+        Some(_) => vec![],
+        None => dir.split('/').filter(|part| !part.is_empty()).collect(),
+    };
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Where the text of one text element sits in the part.
+struct Span {
+    /// The text between the start and end tags.
+    content: Range<usize>,
+    /// Position of the `>` closing the start tag.
+    tag_end: usize,
+    space_preserved: bool,
+    text: String,
+}
+
+/// A paragraph, or a text frame of them, split into segments: runs next to
+/// each other with the same formatting share a segment, so Word splitting runs
+/// for spell checking or revision marks does not clutter the chunk with
+/// `TOK_SEP`.
+#[derive(Default)]
+struct Paragraph {
+    segments: Vec<Vec<Span>>,
+    /// Formatting of the last segment while later runs may still join it.
+    open: Option<String>,
+    /// Whether it sits in an `mc:Fallback`.
+    mirror: bool,
+}
+
+impl Paragraph {
+    fn text(&self) -> String {
+        self.segments
+            .iter()
+            .map(|spans| {
+                spans
+                    .iter()
+                    .map(|span| span.text.as_str())
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join(&TOK_SEP.to_string())
+    }
+}
+
+/// An XML part with the text of its paragraphs located.
+pub struct XmlPart {
+    xml: String,
+    name: String,
+    space_preserve: bool,
+    /// In the order their ends appear, so a text box comes before the
+    /// paragraph holding it.
+    paragraphs: Vec<Paragraph>,
+}
+
+impl XmlPart {
+    pub fn parse(name: &str, xml: String, dialect: &Dialect) -> Result<Self> {
+        let namespaces = Namespaces::read(name, &xml)?;
+        XmlPart::parse_fragment(name, xml, dialect, &namespaces)
+    }
+
+    /// A piece of a part, such as one shared string, whose prefixes are bound
+    /// in the part around it.
+    pub fn parse_fragment(
+        name: &str,
+        xml: String,
+        dialect: &Dialect,
+        namespaces: &Namespaces,
+    ) -> Result<Self> {
+        let mut paragraphs = vec![];
+        // paragraphs being read; text boxes nest them
+        let mut open: Vec<Paragraph> = vec![];
+        // text frames being read, with how many paragraphs were open around them
+        let mut frames: Vec<(Paragraph, usize)> = vec![];
+        let mut skipped = 0;
+        let mut fallbacks = 0;
+        let mut element = String::new();
+        let mut element_start = 0;
+        let mut space_preserved = false;
+        let mut run_props = String::new();
+        let mut run_props_start: Option<usize> = None;
+        let mut text_start: Option<(usize, usize, bool)> = None;
+
+        // anything but runs and their text ends the segment being read
+        let split = |open: &mut Vec<Paragraph>| {
+            if let Some(paragraph) = open.last_mut() {
+                paragraph.open = None;
+            }
+        };
+
+        for token in Tokenizer::from(xml.as_str()) {
+            let token = token.map_err(|err| corrupt(name, err))?;
+            match token {
+                Token::ElementStart {
+                    prefix,
+                    local,
+                    span,
+                } => {
+                    element = namespaces.name(prefix.as_str(), local.as_str());
+                    element_start = span.start();
+                    space_preserved = false;
+                }
+                Token::Attribute {
+                    prefix,
+                    local,
+                    value,
+                    ..
+                } if prefix.as_str() == "xml" && local.as_str() == "space" => {
+                    space_preserved = value.as_str() == "preserve";
+                }
+                Token::ElementEnd { end, span } => {
+                    let closing = match end {
+                        ElementEnd::Open => None,
+                        ElementEnd::Empty => Some(element.clone()),
+                        ElementEnd::Close(prefix, local) => {
+                            Some(namespaces.name(prefix.as_str(), local.as_str()))
+                        }
+                    };
+                    let name = closing.as_deref().unwrap_or(&element);
+                    let opening = !matches!(end, ElementEnd::Close(..));
+
+                    if opening && name == dialect.run_props {
+                        run_props_start = Some(element_start);
+                    }
+                    if run_props_start.is_some() {
+                        if closing.as_deref() == Some(dialect.run_props) {
+                            run_props =
+                                xml[run_props_start.unwrap_or_default()..span.end()].to_string();
+                            run_props_start = None;
+                        }
+                        continue;
+                    }
+
+                    if name == dialect.paragraph {
+                        if opening {
+                            open.push(Paragraph {
+                                mirror: fallbacks > 0,
+                                ..Default::default()
+                            });
+                        }
+                        if let Some(paragraph) = closing.as_ref().and_then(|_| open.pop()) {
+                            match frames.last_mut() {
+                                Some((frame, around)) if *around == open.len() => {
+                                    frame.segments.extend(paragraph.segments)
+                                }
+                                _ => paragraphs.push(paragraph),
+                            }
+                            split(&mut open);
+                        }
+                    } else if dialect.frames.contains(&name) {
+                        if opening {
+                            let frame = Paragraph {
+                                mirror: fallbacks > 0,
+                                ..Default::default()
+                            };
+                            frames.push((frame, open.len()));
+                        }
+                        if closing.is_some() {
+                            paragraphs.extend(frames.pop().map(|(frame, _)| frame));
+                        }
+                    } else if name == FALLBACK {
+                        match end {
+                            ElementEnd::Open => fallbacks += 1,
+                            ElementEnd::Close(..) => fallbacks -= 1,
+                            ElementEnd::Empty => {}
+                        }
+                        split(&mut open);
+                    } else if dialect.skipped.contains(&name) {
+                        match end {
+                            ElementEnd::Open => skipped += 1,
+                            ElementEnd::Close(..) => skipped -= 1,
+                            ElementEnd::Empty => {}
+                        }
+                        split(&mut open);
+                    } else if name == dialect.run {
+                        if opening {
+                            run_props.clear();
+                        }
+                    } else if name == dialect.text {
+                        match end {
+                            ElementEnd::Open if skipped == 0 => {
+                                text_start = Some((span.start(), span.end(), space_preserved))
+                            }
+                            ElementEnd::Close(..) => {
+                                let Some((tag_end, start, space_preserved)) = text_start.take()
+                                else {
+                                    continue;
+                                };
+                                let Some(paragraph) = open.last_mut() else {
+                                    continue;
+                                };
+                                let span = Span {
+                                    content: start..span.start(),
+                                    tag_end,
+                                    space_preserved,
+                                    text: unescape(&xml[start..span.start()]),
+                                };
+                                match (&paragraph.open, paragraph.segments.last_mut()) {
+                                    (Some(props), Some(spans)) if *props == run_props => {
+                                        spans.push(span)
+                                    }
+                                    _ => {
+                                        paragraph.segments.push(vec![span]);
+                                        paragraph.open = Some(run_props.clone());
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    } else if !dialect.transparent.contains(&name) {
+                        split(&mut open);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(XmlPart {
+            xml,
+            name: name.to_string(),
+            space_preserve: dialect.space_preserve,
+            paragraphs,
+        })
+    }
+
+    /// Paragraphs with some text to translate, as chunks or mirrors.
+    fn translatable(&self, mirror: bool) -> impl Iterator<Item = &Paragraph> {
+        self.paragraphs.iter().filter(move |paragraph| {
+            paragraph.mirror == mirror && !paragraph.text().replace(TOK_SEP, "").trim().is_empty()
+        })
+    }
+
+    /// The chunk of a part holding one paragraph, such as a cell string.
+    pub fn text(&self) -> Option<String> {
+        self.translatable(false).next().map(Paragraph::text)
+    }
+
+    pub fn collect(&self, tasks: &mut Tasks, task_type: TaskType) {
+        self.translatable(false)
+            .for_each(|paragraph| tasks.add(paragraph.text(), task_type.clone()));
+    }
+
+    /// A part holding copies of text translated in other parts, such as the
+    /// drawing of a SmartArt diagram: it has no chunks of its own and every
+    /// paragraph mirrors the translation of the same text.
+    pub fn mirrored(mut self) -> Self {
+        for paragraph in &mut self.paragraphs {
+            paragraph.mirror = true;
+        }
+        self
+    }
+
+    /// The part with the translations written into its runs. A segment's
+    /// translation goes into its first run; the other runs are emptied.
+    pub fn apply(&self, tasks: &mut Tasks, task_type: TaskType) -> Result<String> {
+        self.apply_with(tasks, task_type, &mut HashMap::new())
+    }
+
+    /// `apply` for a part of a package: `translations` holds the chunks
+    /// translated so far by their text without markers; mirrors are looked up
+    /// in it and this part's chunks are added.
+    pub fn apply_with(
+        &self,
+        tasks: &mut Tasks,
+        task_type: TaskType,
+        translations: &mut HashMap<String, String>,
+    ) -> Result<String> {
+        let mut edits: Vec<(Range<usize>, String)> = vec![];
+        for paragraph in self.translatable(false) {
+            let (index, chunk) = tasks.collect(task_type.clone())?;
+            let translated = chunk.split(TOK_SEP).collect::<Vec<_>>();
+            if translated.len() != paragraph.segments.len() {
+                return Err(TrenError::SegmentMismatch {
+                    index,
+                    task_type,
+                    expected: paragraph.segments.len(),
+                    found: translated.len(),
+                });
+            }
+            self.write(paragraph, &translated, &mut edits);
+            translations.insert(paragraph.text().replace(TOK_SEP, ""), chunk);
+        }
+        // a fallback split into other runs than its choice gets the whole
+        // translation in its first segment
+        for paragraph in self.translatable(true) {
+            let Some(chunk) = translations.get(&paragraph.text().replace(TOK_SEP, "")) else {
+                continue;
+            };
+            let mut translated = chunk.split(TOK_SEP).collect::<Vec<_>>();
+            let plain = chunk.replace(TOK_SEP, "");
+            if translated.len() != paragraph.segments.len() {
+                translated = vec![""; paragraph.segments.len()];
+                translated[0] = &plain;
+            }
+            self.write(paragraph, &translated, &mut edits);
+        }
+
+        edits.sort_by_key(|(range, _)| (range.start, range.end));
+        let mut xml = String::with_capacity(self.xml.len());
+        let mut copied = 0;
+        for (range, text) in edits {
+            xml.push_str(&self.xml[copied..range.start]);
+            xml.push_str(&text);
+            copied = range.end;
+        }
+        xml.push_str(&self.xml[copied..]);
+        Ok(xml)
+    }
+
+    /// Edits writing each segment's translation into its runs.
+    fn write(
+        &self,
+        paragraph: &Paragraph,
+        translated: &[&str],
+        edits: &mut Vec<(Range<usize>, String)>,
+    ) {
+        for (spans, text) in paragraph.segments.iter().zip(translated) {
+            for (i, span) in spans.iter().enumerate() {
+                let text = if i == 0 { text } else { "" };
+                if self.space_preserve && !span.space_preserved && text.trim().len() != text.len() {
+                    edits.push((
+                        span.tag_end..span.tag_end,
+                        r#" xml:space="preserve""#.into(),
+                    ));
+                }
+                edits.push((span.content.clone(), escape(text)));
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// What the package formats share: the parts translated in place, in the
+/// order of their chunks, and the package they are written back into.
+#[derive(Default)]
+pub struct PackageAST {
+    package: Package,
+    parts: Vec<(XmlPart, TaskType)>,
+    /// Parts rewritten by `apply_mipcs`.
+    translated: HashMap<String, String>,
+}
+
+impl PackageAST {
+    pub fn new(package: Package, parts: Vec<(XmlPart, TaskType)>) -> Self {
+        PackageAST {
+            package,
+            parts,
+            translated: HashMap::new(),
+        }
+    }
+
+    pub fn export(&self, filepath: &Path) -> Result<()> {
+        self.package.write(filepath, &self.translated)
+    }
+
+    pub fn to_mipcs(&self) -> Tasks {
+        let mut tasks = Tasks::new();
+        for (part, task_type) in &self.parts {
+            part.collect(&mut tasks, task_type.clone());
+        }
+        tasks
+    }
+
+    /// Write the chunks into their parts; a mirror takes the translation of
+    /// the same text from any part before it.
+    pub fn apply_mipcs(&mut self, mut mipcs: Tasks) -> Result<()> {
+        let mut translations = HashMap::new();
+        for (part, task_type) in &self.parts {
+            let xml = part.apply_with(&mut mipcs, task_type.clone(), &mut translations)?;
+            self.translated.insert(part.name().to_string(), xml);
+        }
+        Ok(())
+    }
+}
+
+fn qualified(prefix: &str, local: &str) -> String {
+    if prefix.is_empty() {
+        local.to_string()
+    } else {
+        format!("{prefix}:{local}")
+    }
+}
+
+pub fn unescape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        res.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..semi];
+        let ch = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or(entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+        match ch {
+            Some(ch) => {
+                res.push(ch);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                res.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const W: &str = r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" xmlns:mc="http://schemas.openxmlformats.org/markup-compatibility/2006""#;
+
+    /// A zip package of the parts in a fresh temporary directory.
+    pub fn write_package(file_name: &str, parts: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tren-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(file_name);
+        let mut writer = ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for (name, xml) in parts {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut writer, xml.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn part(body: &str) -> XmlPart {
+        let xml = format!("<w:document {W}><w:body>{body}</w:body></w:document>");
+        XmlPart::parse("word/document.xml", xml, &WORDPROCESSING_ML).unwrap()
+    }
+
+    fn chunks(part: &XmlPart) -> Vec<String> {
+        let mut tasks = Tasks::new();
+        part.collect(&mut tasks, TaskType::Main);
+        tasks.main.into()
+    }
+
+    #[test]
+    fn runs_with_the_same_formatting_merge() {
+        let part = part(
+            r#"<w:p><w:r><w:rPr><w:lang w:val="en"/></w:rPr><w:t xml:space="preserve">Hello </w:t></w:r><w:proofErr w:type="spellStart"/><w:r><w:rPr><w:lang w:val="en"/></w:rPr><w:t>wrld</w:t></w:r><w:proofErr w:type="spellEnd"/><w:r><w:rPr><w:b/></w:rPr><w:t>bold</w:t></w:r></w:p>"#,
+        );
+        assert_eq!(chunks(&part), ["Hello wrld𐑙bold"]);
+
+        let mut tasks = Tasks::with_chunks(["Bonjour monde𐑙gras".into()].into(), [].into());
+        let xml = part.apply(&mut tasks, TaskType::Main).unwrap();
+        assert!(xml.contains(r#"<w:t xml:space="preserve">Bonjour monde</w:t>"#));
+        assert!(xml.contains("<w:t></w:t>"));
+        assert!(xml.contains("<w:t>gras</w:t>"));
+    }
+
+    #[test]
+    fn segment_count_mismatch_is_an_error() {
+        let part = part(
+            r#"<w:p><w:r><w:t>plain</w:t></w:r><w:r><w:rPr><w:i/></w:rPr><w:t>italic</w:t></w:r></w:p>"#,
+        );
+        let mut tasks = Tasks::with_chunks(["un seul segment".into()].into(), [].into());
+        let err = part.apply(&mut tasks, TaskType::Main).unwrap_err();
+        assert!(matches!(
+            err,
+            TrenError::SegmentMismatch {
+                index: 0,
+                expected: 2,
+                found: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn spaces_get_preserved_and_text_escaped() {
+        let part = part("<w:p><w:r><w:t>Tom &amp; Jerry</w:t></w:r></w:p>");
+        assert_eq!(chunks(&part), ["Tom & Jerry"]);
+        let mut tasks = Tasks::with_chunks([" <3 & co ".into()].into(), [].into());
+        let xml = part.apply(&mut tasks, TaskType::Main).unwrap();
+        assert!(xml.contains(r#"<w:t xml:space="preserve"> &lt;3 &amp; co </w:t>"#));
+    }
+
+    #[test]
+    fn text_boxes_come_before_their_paragraph() {
+        let part = part(
+            "<w:p><w:r><w:t>Outer</w:t></w:r><w:r><w:drawing><w:txbxContent><w:p><w:r><w:t>Boxed</w:t></w:r></w:p></w:txbxContent></w:drawing></w:r><w:r><w:t>after</w:t></w:r></w:p>",
+        );
+        assert_eq!(chunks(&part), ["Boxed", "Outer𐑙after"]);
+    }
+
+    #[test]
+    fn alternate_content_is_translated_once_and_mirrored() {
+        let box_paragraph = r#"<w:p><w:r><w:t xml:space="preserve">Box </w:t></w:r><w:r><w:rPr><w:b/></w:rPr><w:t>text</w:t></w:r></w:p>"#;
+        let part = part(&format!(
+            "<w:p><w:r><mc:AlternateContent><mc:Choice><w:txbxContent>{box_paragraph}</w:txbxContent></mc:Choice><mc:Fallback><w:txbxContent>{box_paragraph}</w:txbxContent></mc:Fallback></mc:AlternateContent></w:r></w:p>"
+        ));
+        assert_eq!(chunks(&part), ["Box 𐑙text"]);
+
+        let mut tasks = Tasks::with_chunks(["Boîte 𐑙texte".into()].into(), [].into());
+        let xml = part.apply(&mut tasks, TaskType::Main).unwrap();
+        assert_eq!(xml.matches("<w:t>texte</w:t>").count(), 2);
+        assert!(!xml.contains("Box"));
+    }
+
+    #[test]
+    fn elements_match_by_namespace() {
+        let xml = r#"<sst xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><si><t>Plain</t></si></sst>"#;
+        let part = XmlPart::parse("sst.xml", xml.into(), &SPREADSHEET_ML).unwrap();
+        assert_eq!(part.text().as_deref(), Some("Plain"));
+
+        let xml = r#"<x:sst xmlns:x="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><x:si><x:t>Prefixed</x:t></x:si></x:sst>"#;
+        let part = XmlPart::parse("sst.xml", xml.into(), &SPREADSHEET_ML).unwrap();
+        assert_eq!(part.text().as_deref(), Some("Prefixed"));
+
+        let xml = r#"<document xmlns="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><p><r><t>Default</t></r></p></document>"#;
+        let part = XmlPart::parse("document.xml", xml.into(), &WORDPROCESSING_ML).unwrap();
+        assert_eq!(part.text().as_deref(), Some("Default"));
+    }
+
+    #[test]
+    fn relationship_targets_resolve_to_part_names() {
+        assert_eq!(resolve("ppt", "slides/slide1.xml"), "ppt/slides/slide1.xml");
+        assert_eq!(
+            resolve("ppt/slides", "../notesSlides/notesSlide1.xml"),
+            "ppt/notesSlides/notesSlide1.xml"
+        );
+        assert_eq!(
+            resolve("xl", "/xl/sharedStrings.xml"),
+            "xl/sharedStrings.xml"
+        );
+    }
+
+    #[test]
+    fn package_keeps_unchanged_parts() {
+        let path = write_package(
+            "in.docx",
+            &[
+                ("word/document.xml", "<old/>"),
+                ("word/styles.xml", "<styles/>"),
+                (
+                    "word/_rels/document.xml.rels",
+                    r#"<Relationships><Relationship Id="rId1" Type="t/header" Target="header1.xml"/><Relationship Id="rId2" Type="t/hyperlink" Target="https://example.com" TargetMode="External"/></Relationships>"#,
+                ),
+            ],
+        );
+        let package = Package::read(&path).unwrap();
+        assert_eq!(
+            package.relationships("word/document.xml").unwrap(),
+            [(
+                "rId1".to_string(),
+                "t/header".to_string(),
+                "word/header1.xml".to_string()
+            )]
+        );
+
+        let output = path.with_file_name("out.docx");
+        let replaced = HashMap::from([("word/document.xml".to_string(), "<new/>".to_string())]);
+        package.write(&output, &replaced).unwrap();
+        let written = Package::read(&output).unwrap();
+        assert_eq!(written.names().unwrap(), package.names().unwrap());
+        assert_eq!(
+            written.part("word/document.xml").unwrap().unwrap(),
+            "<new/>"
+        );
+        assert_eq!(
+            written.part("word/styles.xml").unwrap().unwrap(),
+            "<styles/>"
+        );
+        assert_eq!(written.part("word/missing.xml").unwrap(), None);
+    }
+
+    #[test]
+    fn entities_unescape() {
+        assert_eq!(
+            unescape("a &lt;b&gt; &amp; &#233;&#x4E2D; &bogus;"),
+            "a <b> & é中 &bogus;"
+        );
+        assert_eq!(escape("a <b> & c"), "a &lt;b&gt; &amp; c");
+    }
+}
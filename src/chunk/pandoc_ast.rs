@@ -1,192 +1,552 @@
 use crate::chunk::{AST, TOK_SEP, TaskType, Tasks};
-use anyhow::{Result, anyhow};
+use crate::error::{Result, TrenError};
 use pandoc_types::definition::{Inline, *};
 use serde_json::json;
+use std::collections::HashMap;
 use std::path::Path;
 use std::vec::IntoIter;
 
-fn collect_ins(bs: &Vec<Block>, tasks: &mut Tasks, mode: &Option<TaskType>) {
-    bs.iter().for_each(|b| match b {
-        Block::Plain(ins) | Block::Para(ins) | Block::Header(_, _, ins) => {
-            fn inlines_to_strings(ins: &Vec<Inline>, tasks: &mut Tasks) -> Vec<String> {
-                ins.iter()
-                    .flat_map(|inline| match inline {
-                        Inline::Str(str) | Inline::RawInline(_, str) => {
-                            vec![str.clone()]
-                        }
-                        Inline::Code(_, _)
-                        | Inline::Space
-                        | Inline::SoftBreak
-                        | Inline::LineBreak
-                        | Inline::Math(_, _) => vec![],
-                        Inline::Note(bs) => {
-                            collect_ins(bs, tasks, &Some(TaskType::Side));
-                            vec![]
-                        }
-                        Inline::Cite(_, _ins) => todo!(),
-                        Inline::Span(_, ins)
-                        | Inline::Link(_, ins, _)
-                        | Inline::Image(_, ins, _)
-                        | Inline::Emph(ins)
-                        | Inline::Strong(ins)
-                        | Inline::Underline(ins)
-                        | Inline::Strikeout(ins)
-                        | Inline::Superscript(ins)
-                        | Inline::Subscript(ins)
-                        | Inline::SmallCaps(ins)
-                        | Inline::Quoted(_, ins) => inlines_to_strings(ins, tasks),
-                    })
-                    .collect()
+fn inlines_to_strings(ins: &[Inline], tasks: &mut Tasks) -> Vec<String> {
+    ins.iter()
+        .flat_map(|inline| match inline {
+            Inline::Str(str) | Inline::RawInline(_, str) => {
+                vec![str.clone()]
+            }
+            Inline::Code(_, _)
+            | Inline::Space
+            | Inline::SoftBreak
+            | Inline::LineBreak
+            | Inline::Math(_, _) => vec![],
+            Inline::Note(bs) => {
+                collect_ins(bs, tasks, &Some(TaskType::Side));
+                vec![]
+            }
+            // citation keys stay out of the chunk; only the visible
+            // prefix/suffix ("see", "p. 12") is translated in place
+            Inline::Cite(citations, _) => citations
+                .iter()
+                .flat_map(|c| [&c.citation_prefix, &c.citation_suffix])
+                .flat_map(|ins| inlines_to_strings(ins, tasks))
+                .collect(),
+            Inline::Span(_, ins)
+            | Inline::Link(_, ins, _)
+            | Inline::Image(_, ins, _)
+            | Inline::Emph(ins)
+            | Inline::Strong(ins)
+            | Inline::Underline(ins)
+            | Inline::Strikeout(ins)
+            | Inline::Superscript(ins)
+            | Inline::Subscript(ins)
+            | Inline::SmallCaps(ins)
+            | Inline::Quoted(_, ins) => inlines_to_strings(ins, tasks),
+        })
+        .collect()
+}
+
+/// Translated segments of one chunk, handed out in document order; counts how
+/// many the document asks for so a mismatch can be reported afterwards.
+struct Segments {
+    strings: IntoIter<String>,
+    needed: usize,
+}
+
+impl Segments {
+    fn next(&mut self) -> String {
+        self.needed += 1;
+        self.strings.next().unwrap_or_default()
+    }
+}
+
+fn strings_to_inlines(
+    inlines: &mut [Inline],
+    mipcs: &mut Tasks,
+    segments: &mut Segments,
+) -> Result<()> {
+    inlines.iter_mut().try_for_each(|inline| match inline {
+        Inline::Str(i) | Inline::RawInline(_, i) => {
+            *i = segments.next();
+            Ok(())
+        }
+        Inline::Code(_, _)
+        | Inline::Space
+        | Inline::SoftBreak
+        | Inline::LineBreak
+        | Inline::Math(_, _) => Ok(()),
+        Inline::Note(bs) => apply_mipc_to_blocks(bs, mipcs, &Some(TaskType::Side)),
+        Inline::Cite(citations, ins) => {
+            citations.iter_mut().try_for_each(|c| {
+                strings_to_inlines(&mut c.citation_prefix, mipcs, segments)?;
+                strings_to_inlines(&mut c.citation_suffix, mipcs, segments)
+            })?;
+            *ins = cite_text(citations);
+            Ok(())
+        }
+        Inline::Span(_, ins)
+        | Inline::Link(_, ins, _)
+        | Inline::Image(_, ins, _)
+        | Inline::Emph(ins)
+        | Inline::Strong(ins)
+        | Inline::Underline(ins)
+        | Inline::Strikeout(ins)
+        | Inline::Superscript(ins)
+        | Inline::Subscript(ins)
+        | Inline::SmallCaps(ins)
+        | Inline::Quoted(_, ins) => strings_to_inlines(ins, mipcs, segments),
+    })
+}
+
+/// Rendered text of a citation as pandoc's markdown reader writes it, e.g.
+/// `[see @doe99, p. 12]`; writers without citeproc print this instead of the
+/// citations, so it is rebuilt from the translated prefix and suffix.
+fn cite_text(citations: &[Citation]) -> Vec<Inline> {
+    let text = |s: &str| Inline::Str(s.to_string());
+    let (in_text, bracketed): (Vec<_>, Vec<_>) = citations
+        .iter()
+        .partition(|c| c.citation_mode == CitationMode::AuthorInText);
+
+    let mut ins = vec![];
+    in_text.iter().enumerate().for_each(|(i, c)| {
+        if i > 0 {
+            ins.push(text("; "));
+        }
+        ins.push(text(&format!("@{}", c.citation_id)));
+        if !c.citation_suffix.is_empty() {
+            ins.push(text(" ["));
+            ins.extend(c.citation_suffix.iter().cloned());
+            ins.push(text("]"));
+        }
+    });
+    if !bracketed.is_empty() {
+        if !ins.is_empty() {
+            ins.push(text(" "));
+        }
+        ins.push(text("["));
+        bracketed.iter().enumerate().for_each(|(i, c)| {
+            if i > 0 {
+                ins.push(text("; "));
+            }
+            if !c.citation_prefix.is_empty() {
+                ins.extend(c.citation_prefix.iter().cloned());
+                ins.push(text(" "));
             }
+            if c.citation_mode == CitationMode::SuppressAuthor {
+                ins.push(text("-"));
+            }
+            ins.push(text(&format!("@{}", c.citation_id)));
+            ins.extend(c.citation_suffix.iter().cloned());
+        });
+        ins.push(text("]"));
+    }
+    ins
+}
 
-            let mipc = inlines_to_strings(ins, tasks).join(&TOK_SEP.to_string());
-            tasks.add(mipc, mode.clone().unwrap_or(TaskType::Main))
+/// One chunk for a run of inlines, with every string joined by `TOK_SEP`.
+fn collect_inlines(ins: &[Inline], tasks: &mut Tasks, mode: &Option<TaskType>) {
+    let mipc = inlines_to_strings(ins, tasks).join(&TOK_SEP.to_string());
+    tasks.add(mipc, mode.clone().unwrap_or(TaskType::Main))
+}
+
+fn apply_mipc_to_inlines(
+    ins: &mut [Inline],
+    mipcs: &mut Tasks,
+    mode: &Option<TaskType>,
+) -> Result<()> {
+    let task_type = mode.clone().unwrap_or(TaskType::Main);
+    let (index, mipc) = mipcs.collect(task_type.clone())?;
+    let strings = mipc
+        .split(TOK_SEP)
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let found = strings.len();
+    let mut segments = Segments {
+        strings: strings.into_iter(),
+        needed: 0,
+    };
+    strings_to_inlines(ins, mipcs, &mut segments)?;
+    // a chunk with no text at all is an empty string, not zero segments
+    if segments.needed != found && !(segments.needed == 0 && mipc.is_empty()) {
+        return Err(TrenError::SegmentMismatch {
+            index,
+            task_type,
+            expected: segments.needed,
+            found,
+        });
+    }
+    Ok(())
+}
+
+fn collect_ins(bs: &[Block], tasks: &mut Tasks, mode: &Option<TaskType>) {
+    bs.iter().for_each(|b| match b {
+        Block::Plain(ins) | Block::Para(ins) | Block::Header(_, _, ins) => {
+            collect_inlines(ins, tasks, mode)
         }
-        Block::LineBlock(_inss) => todo!(),
+        Block::LineBlock(inss) => inss
+            .iter()
+            .for_each(|ins| collect_inlines(ins, tasks, mode)),
         Block::CodeBlock(_, _) => (),
         Block::RawBlock(_format, _text) => (),
         Block::BlockQuote(bs) | Block::Div(_, bs) => collect_ins(bs, tasks, mode),
-        Block::OrderedList(_, _bss) => todo!(),
-        Block::BulletList(bss) => bss.iter().for_each(|bs| collect_ins(bs, tasks, mode)),
-        Block::DefinitionList(_terms) => todo!(),
+        Block::OrderedList(_, bss) | Block::BulletList(bss) => {
+            bss.iter().for_each(|bs| collect_ins(bs, tasks, mode))
+        }
+        Block::DefinitionList(terms) => terms.iter().for_each(|(term, defs)| {
+            collect_inlines(term, tasks, mode);
+            defs.iter().for_each(|bs| collect_ins(bs, tasks, mode));
+        }),
         Block::HorizontalRule | Block::Null => (),
-        Block::Table(_) => todo!(),
-        Block::Figure(_, _cap, _bs) => todo!(),
+        Block::Table(table) => {
+            collect_caption(&table.caption, tasks, mode);
+            table_rows(table).for_each(|row| collect_row(row, tasks, mode));
+        }
+        Block::Figure(_, caption, bs) => {
+            collect_ins(bs, tasks, mode);
+            collect_caption(caption, tasks, &Some(TaskType::Side));
+        }
     });
 }
 
-fn apply_mipc_to_blocks(bs_ref: &mut Vec<Block>, mipcs: &mut Tasks, mode: &Option<TaskType>) {
-    bs_ref.iter_mut().for_each(|b_ref| match b_ref {
+fn apply_mipc_to_blocks(
+    bs_ref: &mut [Block],
+    mipcs: &mut Tasks,
+    mode: &Option<TaskType>,
+) -> Result<()> {
+    bs_ref.iter_mut().try_for_each(|b_ref| match b_ref {
         Block::Plain(ins) | Block::Para(ins) | Block::Header(_, _, ins) => {
-            let strings = mipcs
-                .collect(mode.clone().unwrap_or(TaskType::Main))
-                // .next()
-                // .expect("mismatch")
-                .split(TOK_SEP)
-                .map(|s| s.to_string())
-                .collect::<Vec<_>>();
-
-            fn strings_to_inlines(
-                inlines: &mut Vec<Inline>,
-                mipcs: &mut Tasks,
-                str_iter: &mut IntoIter<String>,
-                mode: &Option<TaskType>,
-            ) {
-                inlines.iter_mut().for_each(|inline| match inline {
-                    Inline::Str(i) | Inline::RawInline(_, i) => {
-                        *i = str_iter.next().expect("mismatch");
-                    }
-                    Inline::Code(_, _)
-                    | Inline::Space
-                    | Inline::SoftBreak
-                    | Inline::LineBreak
-                    | Inline::Math(_, _) => (),
-                    Inline::Note(bs) => apply_mipc_to_blocks(bs, mipcs, &Some(TaskType::Side)),
-                    Inline::Cite(_, _ins) => todo!(),
-                    Inline::Span(_, ins)
-                    | Inline::Link(_, ins, _)
-                    | Inline::Image(_, ins, _)
-                    | Inline::Emph(ins)
-                    | Inline::Strong(ins)
-                    | Inline::Underline(ins)
-                    | Inline::Strikeout(ins)
-                    | Inline::Superscript(ins)
-                    | Inline::Subscript(ins)
-                    | Inline::SmallCaps(ins)
-                    | Inline::Quoted(_, ins) => strings_to_inlines(ins, mipcs, str_iter, mode),
-                });
-            }
-
-            strings_to_inlines(ins, mipcs, &mut strings.into_iter(), mode);
+            apply_mipc_to_inlines(ins, mipcs, mode)
         }
-        Block::LineBlock(_inss) => todo!(),
-        Block::CodeBlock(_, _) => (),
-        Block::RawBlock(_format, _text) => (),
+        Block::LineBlock(inss) => inss
+            .iter_mut()
+            .try_for_each(|ins| apply_mipc_to_inlines(ins, mipcs, mode)),
+        Block::CodeBlock(_, _) => Ok(()),
+        Block::RawBlock(_format, _text) => Ok(()),
         Block::BlockQuote(bs) | Block::Div(_, bs) => apply_mipc_to_blocks(bs, mipcs, mode),
-        Block::OrderedList(_, _bss) => todo!(),
-        Block::BulletList(bss) => bss
+        Block::OrderedList(_, bss) | Block::BulletList(bss) => bss
             .iter_mut()
-            .for_each(|bs| apply_mipc_to_blocks(bs, mipcs, mode)),
-        Block::DefinitionList(_terms) => todo!(),
-        Block::HorizontalRule | Block::Null => (),
-        Block::Table(_) => todo!(),
-        Block::Figure(_, _cap, _bs) => todo!(),
+            .try_for_each(|bs| apply_mipc_to_blocks(bs, mipcs, mode)),
+        Block::DefinitionList(terms) => terms.iter_mut().try_for_each(|(term, defs)| {
+            apply_mipc_to_inlines(term, mipcs, mode)?;
+            defs.iter_mut()
+                .try_for_each(|bs| apply_mipc_to_blocks(bs, mipcs, mode))
+        }),
+        Block::HorizontalRule | Block::Null => Ok(()),
+        Block::Table(table) => {
+            apply_mipc_to_caption(&mut table.caption, mipcs, mode)?;
+            table_rows_mut(table)
+                .flat_map(|row| &mut row.cells)
+                .try_for_each(|cell| apply_mipc_to_blocks(&mut cell.content, mipcs, mode))
+        }
+        Block::Figure(_, caption, bs) => {
+            apply_mipc_to_blocks(bs, mipcs, mode)?;
+            apply_mipc_to_caption(caption, mipcs, &Some(TaskType::Side))
+        }
     })
 }
 
-fn clean_space(bs: &mut Vec<Block>) {
-    bs.iter_mut().for_each(|b| match b {
-        Block::Plain(ins) | Block::Para(ins) | Block::Header(_, _, ins) => {
-            fn clean_space_inlines(ins: &mut Vec<Inline>) {
-                ins.iter_mut().for_each(|inline| {
-                    match inline {
-                        Inline::Space => *inline = Inline::Str(" ".to_string()),
-                        Inline::Link(_, ins, _)
-                        | Inline::Image(_, ins, _)
-                        | Inline::Emph(ins)
-                        | Inline::Strong(ins)
-                        | Inline::Underline(ins)
-                        | Inline::Strikeout(ins)
-                        | Inline::Superscript(ins)
-                        | Inline::Subscript(ins)
-                        | Inline::SmallCaps(ins)
-                        | Inline::Quoted(_, ins) => clean_space_inlines(ins),
-                        Inline::Note(bs) => clean_space(bs),
-                        _ => (),
-                    };
+/// Every row of a table in reading order: head, each body's head and body,
+/// then foot. Column specs and cell attributes are left alone; only the cell
+/// contents are chunked, each on its own.
+fn table_rows(table: &Table) -> impl Iterator<Item = &Row> {
+    table
+        .head
+        .rows
+        .iter()
+        .chain(
+            table
+                .bodies
+                .iter()
+                .flat_map(|b| b.head.iter().chain(&b.body)),
+        )
+        .chain(&table.foot.rows)
+}
+
+/// Chunks of the cells of a row, each with the other cells of the row as
+/// context, as a spreadsheet row is given.
+fn collect_row(row: &Row, tasks: &mut Tasks, mode: &Option<TaskType>) {
+    let task_type = mode.clone().unwrap_or(TaskType::Main);
+    let texts = row
+        .cells
+        .iter()
+        .map(|cell| cell_text(&cell.content))
+        .collect::<Vec<_>>();
+    for (i, cell) in row.cells.iter().enumerate() {
+        let start = tasks.count(&task_type);
+        collect_ins(&cell.content, tasks, mode);
+        let context = texts
+            .iter()
+            .enumerate()
+            .filter(|(j, text)| *j != i && !text.trim().is_empty())
+            .map(|(_, text)| text.clone())
+            .collect::<Vec<_>>();
+        for index in start..tasks.count(&task_type) {
+            tasks
+                .context
+                .insert((task_type.clone(), index), context.clone());
+        }
+    }
+}
+
+/// The text of a cell, without its notes.
+fn cell_text(bs: &[Block]) -> String {
+    let mut tasks = Tasks::new();
+    collect_ins(bs, &mut tasks, &Some(TaskType::Main));
+    Vec::from(tasks.main).join("\n").replace(TOK_SEP, "")
+}
+
+fn table_rows_mut(table: &mut Table) -> impl Iterator<Item = &mut Row> {
+    table
+        .head
+        .rows
+        .iter_mut()
+        .chain(
+            table
+                .bodies
+                .iter_mut()
+                .flat_map(|b| b.head.iter_mut().chain(&mut b.body)),
+        )
+        .chain(&mut table.foot.rows)
+}
+
+fn collect_caption(caption: &Caption, tasks: &mut Tasks, mode: &Option<TaskType>) {
+    if let Some(short) = &caption.short {
+        collect_inlines(short, tasks, mode);
+    }
+    collect_ins(&caption.long, tasks, mode);
+}
+
+fn apply_mipc_to_caption(
+    caption: &mut Caption,
+    mipcs: &mut Tasks,
+    mode: &Option<TaskType>,
+) -> Result<()> {
+    if let Some(short) = &mut caption.short {
+        apply_mipc_to_inlines(short, mipcs, mode)?;
+    }
+    apply_mipc_to_blocks(&mut caption.long, mipcs, mode)
+}
+
+/// Meta values are chunked like blocks: inlines and strings as one chunk each,
+/// lists item by item and maps field by field in key order.
+fn collect_meta(value: &MetaValue, tasks: &mut Tasks) {
+    match value {
+        MetaValue::MetaInlines(ins) => collect_inlines(ins, tasks, &None),
+        MetaValue::MetaBlocks(bs) => collect_ins(bs, tasks, &None),
+        MetaValue::MetaString(str) => tasks.add(str.clone(), TaskType::Main),
+        MetaValue::MetaList(values) => values.iter().for_each(|v| collect_meta(v, tasks)),
+        MetaValue::MetaMap(map) => sorted_keys(map)
+            .into_iter()
+            .for_each(|key| collect_meta(&map[key], tasks)),
+        MetaValue::MetaBool(_) => (),
+    }
+}
+
+fn apply_mipc_to_meta(value: &mut MetaValue, mipcs: &mut Tasks) -> Result<()> {
+    match value {
+        MetaValue::MetaInlines(ins) => apply_mipc_to_inlines(ins, mipcs, &None),
+        MetaValue::MetaBlocks(bs) => apply_mipc_to_blocks(bs, mipcs, &None),
+        MetaValue::MetaString(str) => {
+            *str = mipcs.collect(TaskType::Main)?.1;
+            Ok(())
+        }
+        MetaValue::MetaList(values) => values
+            .iter_mut()
+            .try_for_each(|v| apply_mipc_to_meta(v, mipcs)),
+        MetaValue::MetaMap(map) => {
+            let keys = sorted_keys(map).into_iter().cloned().collect::<Vec<_>>();
+            keys.iter()
+                .try_for_each(|key| apply_mipc_to_meta(map.get_mut(key).unwrap(), mipcs))
+        }
+        MetaValue::MetaBool(_) => Ok(()),
+    }
+}
+
+fn sorted_keys(map: &HashMap<String, MetaValue>) -> Vec<&String> {
+    let mut keys = map.keys().collect::<Vec<_>>();
+    keys.sort();
+    keys
+}
+
+fn clean_space_inlines(ins: &mut Vec<Inline>) {
+    ins.iter_mut().for_each(|inline| {
+        match inline {
+            Inline::Space => *inline = Inline::Str(" ".to_string()),
+            Inline::Link(_, ins, _)
+            | Inline::Image(_, ins, _)
+            | Inline::Emph(ins)
+            | Inline::Strong(ins)
+            | Inline::Underline(ins)
+            | Inline::Strikeout(ins)
+            | Inline::Superscript(ins)
+            | Inline::Subscript(ins)
+            | Inline::SmallCaps(ins)
+            | Inline::Quoted(_, ins) => clean_space_inlines(ins),
+            Inline::Note(bs) => clean_space(bs),
+            Inline::Cite(citations, ins) => {
+                citations.iter_mut().for_each(|c| {
+                    clean_space_inlines(&mut c.citation_prefix);
+                    clean_space_inlines(&mut c.citation_suffix);
                 });
-                *ins = ins
-                    .iter()
-                    .fold(vec![], |mut accs: Vec<Inline>, inline: &Inline| {
-                        match (accs.last(), inline) {
-                            (None, _) => vec![inline.clone()],
-                            (Some(Inline::Str(str1)), Inline::Str(str2)) => {
-                                *accs.last_mut().unwrap() = Inline::Str(format!("{str1}{str2}"));
-                                accs
-                            }
-                            _ => {
-                                accs.push(inline.clone());
-                                accs
-                            }
-                        }
-                    })
+                clean_space_inlines(ins);
             }
-            clean_space_inlines(ins);
-        }
-        Block::LineBlock(_inss) => todo!(),
+            _ => (),
+        };
+    });
+    *ins = ins
+        .iter()
+        .fold(vec![], |mut accs: Vec<Inline>, inline: &Inline| {
+            match (accs.last(), inline) {
+                (None, _) => vec![inline.clone()],
+                (Some(Inline::Str(str1)), Inline::Str(str2)) => {
+                    *accs.last_mut().unwrap() = Inline::Str(format!("{str1}{str2}"));
+                    accs
+                }
+                _ => {
+                    accs.push(inline.clone());
+                    accs
+                }
+            }
+        })
+}
+
+fn clean_space(bs: &mut [Block]) {
+    bs.iter_mut().for_each(|b| match b {
+        Block::Plain(ins) | Block::Para(ins) | Block::Header(_, _, ins) => clean_space_inlines(ins),
+        Block::LineBlock(inss) => inss.iter_mut().for_each(clean_space_inlines),
         Block::CodeBlock(_, _) => (),
         Block::RawBlock(_format, _text) => (),
         Block::BlockQuote(bs) | Block::Div(_, bs) => clean_space(bs),
-        Block::OrderedList(_, _bss) => todo!(),
-        Block::BulletList(bss) => bss.iter_mut().map(clean_space).collect(),
-        Block::DefinitionList(_terms) => todo!(),
+        Block::OrderedList(_, bss) | Block::BulletList(bss) => {
+            bss.iter_mut().for_each(|bs| clean_space(bs))
+        }
+        Block::DefinitionList(terms) => terms.iter_mut().for_each(|(term, defs)| {
+            clean_space_inlines(term);
+            defs.iter_mut().for_each(|bs| clean_space(bs));
+        }),
         Block::HorizontalRule | Block::Null => (),
-        Block::Table(_) => todo!(),
-        Block::Figure(_, _cap, _bs) => todo!(),
+        Block::Table(table) => {
+            clean_space_caption(&mut table.caption);
+            table_rows_mut(table)
+                .flat_map(|row| &mut row.cells)
+                .for_each(|cell| clean_space(&mut cell.content));
+        }
+        Block::Figure(_, caption, bs) => {
+            clean_space(bs);
+            clean_space_caption(caption);
+        }
     });
 }
 
+fn clean_space_caption(caption: &mut Caption) {
+    if let Some(short) = &mut caption.short {
+        clean_space_inlines(short);
+    }
+    clean_space(&mut caption.long);
+}
+
+fn clean_space_meta(value: &mut MetaValue) {
+    match value {
+        MetaValue::MetaInlines(ins) => clean_space_inlines(ins),
+        MetaValue::MetaBlocks(bs) => clean_space(bs),
+        MetaValue::MetaList(values) => values.iter_mut().for_each(clean_space_meta),
+        MetaValue::MetaMap(map) => map.values_mut().for_each(clean_space_meta),
+        MetaValue::MetaString(_) | MetaValue::MetaBool(_) => (),
+    }
+}
+
+/// Pandoc format name for a file, from its extension.
+fn format_of(filepath: &Path, reading: bool) -> Option<&'static str> {
+    let ext = filepath.extension()?.to_str()?.to_lowercase();
+    Some(match ext.as_str() {
+        "md" | "markdown" | "mkd" | "mdown" => "markdown",
+        "txt" if reading => "markdown",
+        "txt" => "plain",
+        "html" | "htm" | "xhtml" => "html",
+        "docx" => "docx",
+        "odt" => "odt",
+        "rtf" => "rtf",
+        "epub" => "epub",
+        "tex" | "latex" | "ltx" => "latex",
+        "rst" => "rst",
+        "org" => "org",
+        "adoc" | "asciidoc" => "asciidoc",
+        "textile" => "textile",
+        "wiki" | "mediawiki" => "mediawiki",
+        "dbk" | "docbook" => "docbook",
+        "ipynb" => "ipynb",
+        "typ" => "typst",
+        "fb2" => "fb2",
+        "opml" => "opml",
+        "json" => "json",
+        _ => return None,
+    })
+}
+
 #[derive(Clone, Default)]
 pub struct PandocAST {
     ast: Vec<Block>,
+    /// Input format; guessed by pandoc from the input extension if unset.
+    from: Option<String>,
+    /// Output format; chosen from the output extension if unset.
+    to: Option<String>,
+    meta: HashMap<String, MetaValue>,
+    /// Meta fields translated ahead of the body, in this order.
+    meta_keys: Vec<String>,
+}
+
+impl PandocAST {
+    pub fn with_formats(from: Option<String>, to: Option<String>) -> Self {
+        PandocAST {
+            from,
+            to,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_meta_keys(self, meta_keys: Vec<String>) -> Self {
+        PandocAST { meta_keys, ..self }
+    }
+
+    /// Format `export` writes to `filepath`; check it before translating
+    /// rather than fail after.
+    pub fn output_format(&self, filepath: &Path) -> Result<String> {
+        self.to
+            .clone()
+            .or(format_of(filepath, false).map(String::from))
+            .ok_or(TrenError::Config(format!(
+                "cannot tell the output format of {}; set it with --to",
+                filepath.display()
+            )))
+    }
 }
 
 impl AST for PandocAST {
     fn import(&mut self, filepath: &Path) -> Result<()> {
         let mut pandoc = pandoc::new();
         pandoc.set_input(pandoc::InputKind::Files(vec![filepath.to_path_buf()]));
+        if let Some(from) = self
+            .from
+            .clone()
+            .or(format_of(filepath, true).map(String::from))
+        {
+            pandoc.set_input_format(pandoc::InputFormat::Other(from), vec![]);
+        }
         pandoc.set_output_format(pandoc::OutputFormat::Json, vec![]);
         pandoc.set_output(pandoc::OutputKind::Pipe);
 
         let result = pandoc.execute()?;
         let result_buf = match result {
             pandoc::PandocOutput::ToBuffer(buf) => Ok(buf),
-            _ => Err(anyhow!("buf not found?")),
+            _ => Err(TrenError::Pandoc("no JSON output from pandoc".to_string())),
         }?;
-        let ast = serde_json::from_str::<Pandoc>(&result_buf)?;
+        let ast =
+            serde_json::from_str::<Pandoc>(&result_buf).map_err(|err| TrenError::Unsupported {
+                node: err.to_string(),
+                location: format!("column {} of the pandoc JSON", err.column()),
+            })?;
         self.ast = ast.blocks;
+        self.meta = ast.meta;
 
         clean_space(&mut self.ast);
+        self.meta.values_mut().for_each(clean_space_meta);
         // println!("{:#?}", self.ast);
 
         Ok(())
@@ -197,13 +557,16 @@ impl AST for PandocAST {
         pandoc.set_input(pandoc::InputKind::Pipe(
             json!({
                 "pandoc-api-version": [1,23,1],
-                "meta": {},
+                "meta": &self.meta,
                 "blocks": &self.ast
             })
             .to_string(),
         ));
         pandoc.set_input_format(pandoc::InputFormat::Json, vec![]);
-        pandoc.set_output_format(pandoc::OutputFormat::Markdown, vec![]);
+        pandoc.set_output_format(
+            pandoc::OutputFormat::Other(self.output_format(filepath)?),
+            vec![],
+        );
         pandoc.set_output(pandoc::OutputKind::File(filepath.to_path_buf()));
 
         pandoc.execute()?;
@@ -212,12 +575,226 @@ impl AST for PandocAST {
 
     fn to_mipcs(&self) -> Tasks {
         let mut tasks = Tasks::new();
+        self.meta_keys
+            .iter()
+            .filter_map(|key| self.meta.get(key))
+            .for_each(|value| collect_meta(value, &mut tasks));
         collect_ins(&self.ast, &mut tasks, &None);
         tasks
     }
 
     fn apply_mipcs(&mut self, mut mipcs: Tasks) -> Result<()> {
-        apply_mipc_to_blocks(&mut self.ast, &mut mipcs, &None);
-        Ok(())
+        for key in &self.meta_keys {
+            if let Some(value) = self.meta.get_mut(key) {
+                apply_mipc_to_meta(value, &mut mipcs)?;
+            }
+        }
+        apply_mipc_to_blocks(&mut self.ast, &mut mipcs, &None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Inline {
+        Inline::Str(s.to_string())
+    }
+
+    fn plain(s: &str) -> Vec<Block> {
+        vec![Block::Plain(vec![text(s)])]
+    }
+
+    fn row(cells: &[&str]) -> Row {
+        Row {
+            attr: Attr::default(),
+            cells: cells
+                .iter()
+                .map(|s| Cell {
+                    content: plain(s),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    fn document(ast: Vec<Block>) -> PandocAST {
+        PandocAST {
+            ast,
+            ..Default::default()
+        }
+    }
+
+    fn round_trip(document: &mut PandocAST, f: impl Fn(&str) -> String) -> Tasks {
+        let tasks = document.to_mipcs();
+        document.apply_mipcs(tasks.translated(f)).unwrap();
+        tasks
+    }
+
+    #[test]
+    fn table_cells_are_chunks_with_their_row_as_context() {
+        let mut document = document(vec![Block::Table(Table {
+            caption: Caption {
+                short: None,
+                long: plain("Prices"),
+            },
+            head: TableHead {
+                attr: Attr::default(),
+                rows: vec![row(&["Item", "Price"])],
+            },
+            bodies: vec![TableBody {
+                body: vec![row(&["Chair", ""]), row(&["Table", "Large"])],
+                ..Default::default()
+            }],
+            ..Default::default()
+        })]);
+        let tasks = round_trip(&mut document, str::to_uppercase);
+        assert_eq!(
+            tasks.main,
+            ["Prices", "Item", "Price", "Chair", "", "Table", "Large"]
+        );
+        assert!(!tasks.context.contains_key(&(TaskType::Main, 0)));
+        assert_eq!(tasks.context[&(TaskType::Main, 1)], ["Price"]);
+        assert_eq!(tasks.context[&(TaskType::Main, 3)], Vec::<String>::new());
+        assert_eq!(tasks.context[&(TaskType::Main, 6)], ["Table"]);
+
+        let Block::Table(table) = &document.ast[0] else {
+            panic!("not a table");
+        };
+        assert_eq!(table.caption.long, plain("PRICES"));
+        assert_eq!(table.head.rows[0], row(&["ITEM", "PRICE"]));
+        assert_eq!(table.bodies[0].body[1], row(&["TABLE", "LARGE"]));
+    }
+
+    #[test]
+    fn definition_list_terms_and_definitions_are_chunks() {
+        let mut document = document(vec![Block::DefinitionList(vec![(
+            vec![text("Cat "), Inline::Emph(vec![text("(animal)")])],
+            vec![plain("Purrs."), plain("Sleeps.")],
+        )])]);
+        let tasks = round_trip(&mut document, str::to_uppercase);
+        assert_eq!(tasks.main, ["Cat 𐑙(animal)", "Purrs.", "Sleeps."]);
+        assert_eq!(
+            document.ast,
+            [Block::DefinitionList(vec![(
+                vec![text("CAT "), Inline::Emph(vec![text("(ANIMAL)")])],
+                vec![plain("PURRS."), plain("SLEEPS.")],
+            )])]
+        );
+    }
+
+    #[test]
+    fn citations_keep_their_keys() {
+        let citation = |id: &str, prefix: &str, suffix: &str| Citation {
+            citation_id: id.to_string(),
+            citation_prefix: vec![text(prefix)],
+            citation_suffix: vec![text(suffix)],
+            citation_mode: CitationMode::NormalCitation,
+            citation_note_num: 0,
+            citation_hash: 0,
+        };
+        let cite = Inline::Cite(
+            vec![citation("doe99", "see", ", p. 12")],
+            vec![text("[see @doe99, p. 12]")],
+        );
+        let mut document = document(vec![Block::Para(vec![text("As shown "), cite, text(".")])]);
+        let tasks = round_trip(&mut document, |chunk| {
+            chunk
+                .replace("As shown", "Comme montré")
+                .replace("see", "voir")
+        });
+        assert_eq!(tasks.main, ["As shown 𐑙see𐑙, p. 12𐑙."]);
+
+        let Block::Para(ins) = &document.ast[0] else {
+            panic!("not a paragraph");
+        };
+        let Inline::Cite(citations, rendered) = &ins[1] else {
+            panic!("not a citation");
+        };
+        assert_eq!(citations[0].citation_id, "doe99");
+        assert_eq!(citations[0].citation_prefix, [text("voir")]);
+        assert_eq!(
+            rendered,
+            &[
+                text("["),
+                text("voir"),
+                text(" "),
+                text("@doe99"),
+                text(", p. 12"),
+                text("]")
+            ]
+        );
+    }
+
+    #[test]
+    fn notes_and_figure_captions_are_side_chunks() {
+        let note = Inline::Note(plain("A note."));
+        let mut document = document(vec![
+            Block::Para(vec![text("Body"), note]),
+            Block::Figure(
+                Attr::default(),
+                Caption {
+                    short: None,
+                    long: plain("A figure."),
+                },
+                vec![],
+            ),
+        ]);
+        let tasks = round_trip(&mut document, str::to_uppercase);
+        assert_eq!(tasks.main, ["Body"]);
+        assert_eq!(tasks.sides, ["A note.", "A figure."]);
+        assert_eq!(
+            document.ast[0],
+            Block::Para(vec![text("BODY"), Inline::Note(plain("A NOTE."))])
+        );
+    }
+
+    #[test]
+    fn meta_fields_come_first_in_the_given_order() {
+        let mut document = document(vec![Block::Para(vec![text("Body")])])
+            .with_meta_keys(vec!["title".into(), "author".into()]);
+        document.meta = HashMap::from([
+            (
+                "author".to_string(),
+                MetaValue::MetaList(vec![MetaValue::MetaString("Ann".into())]),
+            ),
+            (
+                "title".to_string(),
+                MetaValue::MetaInlines(vec![text("Hello")]),
+            ),
+            ("date".to_string(), MetaValue::MetaString("Today".into())),
+        ]);
+        let tasks = round_trip(&mut document, str::to_uppercase);
+        assert_eq!(tasks.main, ["Hello", "Ann", "Body"]);
+        assert_eq!(document.meta["date"], MetaValue::MetaString("Today".into()));
+    }
+
+    #[test]
+    fn segment_count_mismatch_is_an_error() {
+        let mut document = document(vec![Block::Para(vec![
+            text("plain "),
+            Inline::Strong(vec![text("bold")]),
+        ])]);
+        let tasks = Tasks::with_chunks(["tout en un".into()].into(), [].into());
+        assert!(matches!(
+            document.apply_mipcs(tasks),
+            Err(TrenError::SegmentMismatch {
+                expected: 2,
+                found: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn spaces_merge_into_strings() {
+        let mut ins = vec![
+            text("Hello"),
+            Inline::Space,
+            text("world"),
+            Inline::SoftBreak,
+        ];
+        clean_space_inlines(&mut ins);
+        assert_eq!(ins, [text("Hello world"), Inline::SoftBreak]);
     }
 }
@@ -0,0 +1,226 @@
+use crate::chunk::ooxml::{PRESENTATION_ML, Package, PackageAST, XmlPart, corrupt};
+use crate::chunk::{AST, TaskType, Tasks};
+use crate::error::{Result, TrenError};
+use std::path::Path;
+use xmlparser::{Token, Tokenizer};
+
+const PRESENTATION: &str = "ppt/presentation.xml";
+const NOTES_SLIDE: &str = "/notesSlide";
+const LAYOUTS: &str = "ppt/slideLayouts/slideLayout";
+
+/// A PowerPoint presentation translated in place: only the text of runs is
+/// replaced, so geometry, animations and media stay as they were.
+#[derive(Default)]
+pub struct PptxAST {
+    /// The slides in presentation order, then their notes and the layouts as
+    /// side chunks.
+    package: PackageAST,
+}
+
+impl AST for PptxAST {
+    fn import(&mut self, filepath: &Path) -> Result<()> {
+        let package = Package::read(filepath)?;
+        let location = filepath.display().to_string();
+        let presentation = package.part(PRESENTATION)?.ok_or(TrenError::Unsupported {
+            node: format!("no {PRESENTATION}"),
+            location: location.clone(),
+        })?;
+        let targets = package.relationships(PRESENTATION)?;
+
+        let mut slides = vec![];
+        for id in slide_ids(&presentation, &location)? {
+            let target = targets.iter().find(|(rel_id, _, _)| *rel_id == id).ok_or(
+                TrenError::Unsupported {
+                    node: format!("no target for slide {id}"),
+                    location: location.clone(),
+                },
+            )?;
+            slides.push(target.2.clone());
+        }
+
+        let mut parts = vec![];
+        let mut notes = vec![];
+        for slide in &slides {
+            let xml = package.part(slide)?.ok_or(TrenError::Unsupported {
+                node: format!("no {slide}"),
+                location: location.clone(),
+            })?;
+            parts.push((
+                XmlPart::parse(slide, xml, &PRESENTATION_ML)?,
+                TaskType::Main,
+            ));
+            notes.extend(
+                package
+                    .relationships(slide)?
+                    .into_iter()
+                    .filter(|(_, kind, _)| kind.ends_with(NOTES_SLIDE))
+                    .map(|(_, _, target)| target),
+            );
+        }
+
+        let mut layouts: Vec<String> = package
+            .names()?
+            .into_iter()
+            .filter(|name| {
+                name.strip_prefix(LAYOUTS)
+                    .and_then(|rest| rest.strip_suffix(".xml"))
+                    .is_some_and(|number| number.chars().all(|c| c.is_ascii_digit()))
+            })
+            .collect();
+        // slideLayout2.xml before slideLayout10.xml
+        layouts.sort_by_key(|name| (name.len(), name.clone()));
+
+        for name in notes.iter().chain(&layouts) {
+            if let Some(xml) = package.part(name)? {
+                parts.push((XmlPart::parse(name, xml, &PRESENTATION_ML)?, TaskType::Side));
+            }
+        }
+        self.package = PackageAST::new(package, parts);
+        Ok(())
+    }
+
+    fn export(&self, filepath: &Path) -> Result<()> {
+        self.package.export(filepath)
+    }
+
+    fn to_mipcs(&self) -> Tasks {
+        self.package.to_mipcs()
+    }
+
+    fn apply_mipcs(&mut self, mipcs: Tasks) -> Result<()> {
+        self.package.apply_mipcs(mipcs)
+    }
+}
+
+/// The relationship ids of the slides listed in `ppt/presentation.xml`.
+fn slide_ids(presentation: &str, location: &str) -> Result<Vec<String>> {
+    let mut ids = vec![];
+    let mut in_slide_id = false;
+    for token in Tokenizer::from(presentation) {
+        match token.map_err(|err| corrupt(location, err))? {
+            Token::ElementStart { local, .. } => in_slide_id = local.as_str() == "sldId",
+            Token::Attribute {
+                prefix,
+                local,
+                value,
+                ..
+            } if in_slide_id && local.as_str() == "id" && !prefix.is_empty() => {
+                ids.push(value.to_string())
+            }
+            Token::ElementEnd { .. } => in_slide_id = false,
+            _ => {}
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ooxml::tests::write_package;
+
+    const NS: &str = r#"xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main" xmlns:p="http://schemas.openxmlformats.org/presentationml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships""#;
+
+    fn shape(paragraphs: &[&str]) -> String {
+        let paragraphs = paragraphs
+            .iter()
+            .map(|text| format!("<a:p><a:r><a:rPr/><a:t>{text}</a:t></a:r></a:p>"))
+            .collect::<String>();
+        format!("<p:sp><p:txBody><a:bodyPr/>{paragraphs}</p:txBody></p:sp>")
+    }
+
+    fn slide(root: &str, shapes: &[String]) -> String {
+        format!(
+            "<p:{root} {NS}><p:cSld><p:spTree>{}</p:spTree></p:cSld></p:{root}>",
+            shapes.concat()
+        )
+    }
+
+    fn rels(targets: &[(&str, &str, &str)]) -> String {
+        let relationships = targets
+            .iter()
+            .map(|(id, kind, target)| {
+                format!(r#"<Relationship Id="{id}" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships{kind}" Target="{target}"/>"#)
+            })
+            .collect::<String>();
+        format!("<Relationships>{relationships}</Relationships>")
+    }
+
+    fn pptx() -> std::path::PathBuf {
+        let number = r#"<p:sp><p:txBody><a:p><a:fld type="slidenum"><a:t>2</a:t></a:fld></a:p></p:txBody></p:sp>"#;
+        write_package(
+            "in.pptx",
+            &[
+                (
+                    PRESENTATION,
+                    &format!(
+                        r#"<p:presentation {NS}><p:sldIdLst><p:sldId id="257" r:id="rId3"/><p:sldId id="256" r:id="rId2"/></p:sldIdLst></p:presentation>"#
+                    ),
+                ),
+                (
+                    "ppt/_rels/presentation.xml.rels",
+                    &rels(&[
+                        ("rId2", "/slide", "slides/slide1.xml"),
+                        ("rId3", "/slide", "slides/slide2.xml"),
+                    ]),
+                ),
+                (
+                    "ppt/slides/slide1.xml",
+                    &slide("sld", &[shape(&["Second"])]),
+                ),
+                (
+                    "ppt/slides/slide2.xml",
+                    &slide("sld", &[shape(&["First", "line two"]), number.to_string()]),
+                ),
+                (
+                    "ppt/slides/_rels/slide2.xml.rels",
+                    &rels(&[("rId1", "/notesSlide", "../notesSlides/notesSlide1.xml")]),
+                ),
+                (
+                    "ppt/notesSlides/notesSlide1.xml",
+                    &slide("notes", &[shape(&["Say hi"])]),
+                ),
+                (
+                    "ppt/slideLayouts/slideLayout10.xml",
+                    &slide("sldLayout", &[shape(&["Ten"])]),
+                ),
+                (
+                    "ppt/slideLayouts/slideLayout2.xml",
+                    &slide("sldLayout", &[shape(&["Two"])]),
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn slides_follow_the_presentation_order() {
+        let mut ast = PptxAST::default();
+        ast.import(&pptx()).unwrap();
+        let tasks = ast.to_mipcs();
+        // a text frame is one chunk; slide numbers are left alone
+        assert_eq!(tasks.main, ["First𐑙line two", "Second"]);
+        assert_eq!(tasks.sides, ["Say hi", "Two", "Ten"]);
+    }
+
+    #[test]
+    fn round_trip_rewrites_only_the_text() {
+        let input = pptx();
+        let mut ast = PptxAST::default();
+        ast.import(&input).unwrap();
+        let tasks = ast.to_mipcs().translated(str::to_uppercase);
+        ast.apply_mipcs(tasks).unwrap();
+        let output = input.with_file_name("out.pptx");
+        ast.export(&output).unwrap();
+
+        let package = Package::read(&output).unwrap();
+        let slide = package.part("ppt/slides/slide2.xml").unwrap().unwrap();
+        assert!(slide.contains("<a:t>FIRST</a:t>"));
+        assert!(slide.contains("<a:t>LINE TWO</a:t>"));
+        assert!(slide.contains("<a:t>2</a:t>"));
+        let notes = package
+            .part("ppt/notesSlides/notesSlide1.xml")
+            .unwrap()
+            .unwrap();
+        assert!(notes.contains("<a:t>SAY HI</a:t>"));
+    }
+}
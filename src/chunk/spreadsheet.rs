@@ -0,0 +1,528 @@
+use crate::chunk::{AST, TOK_SEP, TaskType, Tasks};
+use crate::cli::DocumentArgs;
+use crate::error::{Result, TrenError};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// A cell as read, with its row and column counted from 0.
+pub struct Cell<K> {
+    pub row: usize,
+    pub column: usize,
+    /// The text shown in the cell, given as context to the rest of its row.
+    pub value: String,
+    /// The chunk of a text cell, with `TOK_SEP` between differently formatted
+    /// runs, and where its translation goes. Numbers and formulas have none.
+    pub text: Option<(String, K)>,
+}
+
+pub struct Sheet<K> {
+    pub name: String,
+    /// Cells in reading order: row by row, left to right.
+    pub cells: Vec<Cell<K>>,
+}
+
+/// A spreadsheet format: reads the cells of every sheet, and writes the file
+/// back with only the text of some cells changed.
+pub trait Book: Sized {
+    /// Where the translation of a cell is written.
+    type Key;
+
+    fn read(filepath: &Path) -> Result<Self>;
+    fn sheets(&self) -> &[Sheet<Self::Key>];
+    fn write(&self, filepath: &Path, translations: &[(&Self::Key, &str)]) -> Result<()>;
+}
+
+/// Cells from the top left to the bottom right corner; a corner without a
+/// row number takes the whole column.
+#[derive(Debug)]
+struct CellRange {
+    sheet: Option<String>,
+    rows: RangeInclusive<usize>,
+    columns: RangeInclusive<usize>,
+}
+
+/// Which cells of a spreadsheet are translated, and which of their row are
+/// given as context.
+#[derive(Debug, Default)]
+pub struct Selection {
+    sheets: Vec<String>,
+    columns: Vec<usize>,
+    ranges: Vec<CellRange>,
+    context_columns: Vec<usize>,
+}
+
+impl Selection {
+    pub fn new(document: &DocumentArgs) -> Result<Self> {
+        let columns = |letters: &[String]| {
+            letters
+                .iter()
+                .map(|letters| {
+                    column_index(letters.trim()).ok_or(TrenError::Config(format!(
+                        "{letters} is not a column; use letters such as B or AA"
+                    )))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Selection {
+            sheets: document
+                .sheets
+                .iter()
+                .map(|sheet| sheet.trim().to_string())
+                .collect(),
+            columns: columns(&document.columns)?,
+            ranges: document
+                .ranges
+                .iter()
+                .map(|range| cell_range(range.trim()))
+                .collect::<Result<_>>()?,
+            context_columns: columns(&document.context_columns)?,
+        })
+    }
+
+    fn contains(&self, sheet: &str, row: usize, column: usize) -> bool {
+        if !self.sheets.is_empty() && !self.sheets.iter().any(|name| name == sheet) {
+            return false;
+        }
+        if self.columns.is_empty() && self.ranges.is_empty() {
+            return true;
+        }
+        self.columns.contains(&column)
+            || self.ranges.iter().any(|range| {
+                range.sheet.as_deref().is_none_or(|name| name == sheet)
+                    && range.rows.contains(&row)
+                    && range.columns.contains(&column)
+            })
+    }
+}
+
+/// Column index of letters such as `B` or `AA`.
+pub fn column_index(letters: &str) -> Option<usize> {
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    letters
+        .to_ascii_uppercase()
+        .bytes()
+        .try_fold(0usize, |index, letter| {
+            index
+                .checked_mul(26)?
+                .checked_add((letter - b'A' + 1) as usize)
+        })
+        .map(|index| index - 1)
+}
+
+/// Row and column of a reference such as `B2`; the row is `None` for a bare
+/// column.
+pub fn cell_ref(reference: &str) -> Option<(Option<usize>, usize)> {
+    let digits = reference.find(|c: char| c.is_ascii_digit());
+    let (letters, number) = reference.split_at(digits.unwrap_or(reference.len()));
+    let column = column_index(letters.trim_start_matches('$').trim_end_matches('$'))?;
+    if number.is_empty() {
+        return Some((None, column));
+    }
+    match number.parse::<usize>() {
+        Ok(row) if row > 0 => Some((Some(row - 1), column)),
+        _ => None,
+    }
+}
+
+fn cell_range(range: &str) -> Result<CellRange> {
+    let bad_range = || {
+        TrenError::Config(format!(
+            "{range} is not a cell range; use one such as B2:D40 or Sheet1!B2:B9"
+        ))
+    };
+    let (sheet, cells) = match range.rsplit_once('!') {
+        Some((sheet, cells)) => {
+            let sheet = sheet
+                .strip_prefix('\'')
+                .and_then(|sheet| sheet.strip_suffix('\''))
+                .unwrap_or(sheet);
+            (Some(sheet.to_string()), cells)
+        }
+        None => (None, range),
+    };
+    let (start, end) = cells.split_once(':').unwrap_or((cells, cells));
+    let (top, left) = cell_ref(start).ok_or_else(bad_range)?;
+    let (bottom, right) = cell_ref(end).ok_or_else(bad_range)?;
+    if left > right || top.unwrap_or(0) > bottom.unwrap_or(usize::MAX) {
+        return Err(bad_range());
+    }
+    Ok(CellRange {
+        sheet,
+        rows: top.unwrap_or(0)..=bottom.unwrap_or(usize::MAX),
+        columns: left..=right,
+    })
+}
+
+/// A spreadsheet translated cell by cell: each selected text cell is a chunk
+/// with the other cells of its row as context, and the file is written back
+/// with the same sheets, formulas, numbers and formatting.
+pub struct SpreadsheetAST<B: Book> {
+    book: Option<B>,
+    selection: Selection,
+    /// Sheet and cell indices of the chunks.
+    chunks: Vec<(usize, usize)>,
+    /// Translations of the chunks, in the same order.
+    translations: Vec<String>,
+}
+
+impl<B: Book> SpreadsheetAST<B> {
+    pub fn new(selection: Selection) -> Self {
+        SpreadsheetAST {
+            book: None,
+            selection,
+            chunks: vec![],
+            translations: vec![],
+        }
+    }
+
+    fn cell(&self, (sheet, cell): (usize, usize)) -> Option<&Cell<B::Key>> {
+        self.book.as_ref()?.sheets().get(sheet)?.cells.get(cell)
+    }
+
+    /// The other cells of the row, or only those of the context columns.
+    fn context(&self, (sheet, cell): (usize, usize)) -> Vec<String> {
+        let Some(sheet) = self.book.as_ref().and_then(|book| book.sheets().get(sheet)) else {
+            return vec![];
+        };
+        let row = sheet.cells[cell].row;
+        sheet
+            .cells
+            .iter()
+            .enumerate()
+            .filter(|(i, other)| {
+                *i != cell
+                    && other.row == row
+                    && !other.value.trim().is_empty()
+                    && (self.selection.context_columns.is_empty()
+                        || self.selection.context_columns.contains(&other.column))
+            })
+            .map(|(_, other)| other.value.clone())
+            .collect()
+    }
+}
+
+impl<B: Book + Send> AST for SpreadsheetAST<B> {
+    fn import(&mut self, filepath: &Path) -> Result<()> {
+        let book = B::read(filepath)?;
+        self.chunks = vec![];
+        for (i, sheet) in book.sheets().iter().enumerate() {
+            for (j, cell) in sheet.cells.iter().enumerate() {
+                let translatable = cell
+                    .text
+                    .as_ref()
+                    .is_some_and(|(text, _)| !text.replace(TOK_SEP, "").trim().is_empty());
+                if translatable && self.selection.contains(&sheet.name, cell.row, cell.column) {
+                    self.chunks.push((i, j));
+                }
+            }
+        }
+        self.book = Some(book);
+        Ok(())
+    }
+
+    fn export(&self, filepath: &Path) -> Result<()> {
+        let Some(book) = &self.book else {
+            return Ok(());
+        };
+        let translations = self
+            .chunks
+            .iter()
+            .zip(&self.translations)
+            .filter_map(|(chunk, translation)| {
+                let (_, key) = self.cell(*chunk)?.text.as_ref()?;
+                Some((key, translation.as_str()))
+            })
+            .collect::<Vec<_>>();
+        book.write(filepath, &translations)
+    }
+
+    fn to_mipcs(&self) -> Tasks {
+        let mut tasks = Tasks::new();
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            if let Some((text, _)) = self.cell(*chunk).and_then(|cell| cell.text.as_ref()) {
+                tasks.add(text.clone(), TaskType::Main);
+                tasks
+                    .context
+                    .insert((TaskType::Main, i), self.context(*chunk));
+            }
+        }
+        tasks
+    }
+
+    fn apply_mipcs(&mut self, mut mipcs: Tasks) -> Result<()> {
+        let mut translations = vec![];
+        for chunk in &self.chunks {
+            let (index, translation) = mipcs.collect(TaskType::Main)?;
+            let expected = self
+                .cell(*chunk)
+                .and_then(|cell| cell.text.as_ref())
+                .map_or(1, |(text, _)| text.split(TOK_SEP).count());
+            let found = translation.split(TOK_SEP).count();
+            if found != expected {
+                return Err(TrenError::SegmentMismatch {
+                    index,
+                    task_type: TaskType::Main,
+                    expected,
+                    found,
+                });
+            }
+            translations.push(translation);
+        }
+        self.translations = translations;
+        Ok(())
+    }
+}
+
+/// Whether a cell of a plain text table holds words rather than a number,
+/// an amount, a percentage, a date, a boolean or a formula.
+fn is_text(value: &str) -> bool {
+    let value = value.trim();
+    if value.starts_with('=')
+        || value.parse::<f64>().is_ok()
+        || value.eq_ignore_ascii_case("true")
+        || value.eq_ignore_ascii_case("false")
+        || is_timestamp(value)
+    {
+        return false;
+    }
+    // an amount in a currency code, such as `USD 12.50` or `12,50 EUR`
+    let words = value.split_whitespace().collect::<Vec<_>>();
+    if let [first, second] = words[..] {
+        let code = |word: &str| word.len() == 3 && word.chars().all(|c| c.is_ascii_uppercase());
+        let amount = |word: &str| !word.chars().any(char::is_alphabetic);
+        if code(first) && amount(second) || amount(first) && code(second) {
+            return false;
+        }
+    }
+    // numbers with `$`, `€` or `%` and dates such as 05/01/2024 have no letters
+    value.chars().any(char::is_alphabetic)
+}
+
+/// Whether a value is an ISO 8601 date or time, such as `2024-01-05T09:30Z`.
+fn is_timestamp(value: &str) -> bool {
+    value.starts_with(|c: char| c.is_ascii_digit())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_digit() || "-:.,+ TZ".contains(c))
+}
+
+/// A CSV or TSV file: a single sheet named after the file.
+pub struct Csv {
+    delimiter: u8,
+    crlf: bool,
+    bom: bool,
+    rows: Vec<Vec<String>>,
+    sheets: Vec<Sheet<(usize, usize)>>,
+}
+
+impl Book for Csv {
+    type Key = (usize, usize);
+
+    fn read(filepath: &Path) -> Result<Self> {
+        let bytes = std::fs::read(filepath)?;
+        let bom = bytes.starts_with(b"\xEF\xBB\xBF");
+        let delimiter = match filepath.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("tsv") => b'\t',
+            _ => b',',
+        };
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(delimiter)
+            .from_reader(&bytes[if bom { 3 } else { 0 }..]);
+        let rows = reader
+            .records()
+            .map(|record| Ok(record?.iter().map(String::from).collect()))
+            .collect::<Result<Vec<Vec<String>>>>()?;
+
+        let mut cells = vec![];
+        for (row, values) in rows.iter().enumerate() {
+            for (column, value) in values.iter().enumerate() {
+                cells.push(Cell {
+                    row,
+                    column,
+                    value: value.clone(),
+                    text: is_text(value).then(|| (value.clone(), (row, column))),
+                });
+            }
+        }
+        let name = filepath
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(Csv {
+            delimiter,
+            crlf: bytes.windows(2).any(|pair| pair == b"\r\n"),
+            bom,
+            rows,
+            sheets: vec![Sheet { name, cells }],
+        })
+    }
+
+    fn sheets(&self) -> &[Sheet<Self::Key>] {
+        &self.sheets
+    }
+
+    fn write(&self, filepath: &Path, translations: &[(&Self::Key, &str)]) -> Result<()> {
+        let mut rows = self.rows.clone();
+        for ((row, column), translation) in translations {
+            rows[*row][*column] = translation.to_string();
+        }
+        let mut file = std::fs::File::create(filepath)?;
+        if self.bom {
+            std::io::Write::write_all(&mut file, b"\xEF\xBB\xBF")?;
+        }
+        let mut writer = csv::WriterBuilder::new()
+            .flexible(true)
+            .delimiter(self.delimiter)
+            .terminator(if self.crlf {
+                csv::Terminator::CRLF
+            } else {
+                csv::Terminator::Any(b'\n')
+            })
+            .from_writer(file);
+        for row in rows {
+            writer.write_record(row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(columns: &[&str], ranges: &[&str], context_columns: &[&str]) -> Selection {
+        let list = |items: &[&str]| items.iter().map(|item| item.to_string()).collect();
+        Selection::new(&DocumentArgs {
+            columns: list(columns),
+            ranges: list(ranges),
+            context_columns: list(context_columns),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn csv(text: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("tren-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("items.csv");
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn columns_and_cells_are_referenced_by_letters() {
+        assert_eq!(column_index("A"), Some(0));
+        assert_eq!(column_index("z"), Some(25));
+        assert_eq!(column_index("AA"), Some(26));
+        assert_eq!(column_index("A1"), None);
+        assert_eq!(cell_ref("B2"), Some((Some(1), 1)));
+        assert_eq!(cell_ref("$C$10"), Some((Some(9), 2)));
+        assert_eq!(cell_ref("D"), Some((None, 3)));
+        assert_eq!(cell_ref("B0"), None);
+    }
+
+    #[test]
+    fn ranges_select_cells() {
+        let selection = selection(&["D"], &["B2:C3", "'My sheet'!A:A"], &[]);
+        assert!(selection.contains("Sheet1", 1, 1));
+        assert!(selection.contains("Sheet1", 2, 2));
+        assert!(!selection.contains("Sheet1", 0, 1));
+        assert!(!selection.contains("Sheet1", 3, 1));
+        assert!(selection.contains("Sheet1", 99, 3));
+        assert!(selection.contains("My sheet", 99, 0));
+        assert!(!selection.contains("Sheet1", 99, 0));
+
+        assert!(cell_range("C3:B2").is_err());
+        assert!(cell_range("B2:").is_err());
+        assert!(
+            Selection::new(&DocumentArgs {
+                columns: vec!["1".into()],
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn cells_get_the_other_cells_of_their_row_as_context() {
+        let path = csv("sku,name,description\nA1,Chair,\"Red, comfy\"\n");
+        let mut ast = SpreadsheetAST::<Csv>::new(selection(&["C"], &[], &["A", "B"]));
+        ast.import(&path).unwrap();
+        let tasks = ast.to_mipcs();
+        assert_eq!(tasks.main, ["description", "Red, comfy"]);
+        assert_eq!(tasks.context[&(TaskType::Main, 0)], ["sku", "name"]);
+        assert_eq!(tasks.context[&(TaskType::Main, 1)], ["A1", "Chair"]);
+    }
+
+    #[test]
+    fn csv_round_trip_keeps_numbers_and_line_endings() {
+        let path = csv("\u{FEFF}name,price,when\r\nChair,12.5,2024-01-05\r\nTable,$30,TRUE\r\n");
+        let mut ast = SpreadsheetAST::<Csv>::new(Selection::default());
+        ast.import(&path).unwrap();
+        let tasks = ast.to_mipcs();
+        assert_eq!(tasks.main, ["name", "price", "when", "Chair", "Table"]);
+        ast.apply_mipcs(tasks.translated(str::to_uppercase))
+            .unwrap();
+        let output = path.with_file_name("out.csv");
+        ast.export(&output).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "\u{FEFF}NAME,PRICE,WHEN\r\nCHAIR,12.5,2024-01-05\r\nTABLE,$30,TRUE\r\n"
+        );
+    }
+
+    #[test]
+    fn segment_count_mismatch_is_an_error() {
+        let mut ast = SpreadsheetAST::<Csv>::new(Selection::default());
+        ast.import(&csv("Hello\n")).unwrap();
+        let tasks = Tasks::with_chunks(["Bon𐑙jour".into()].into(), [].into());
+        assert!(matches!(
+            ast.apply_mipcs(tasks),
+            Err(TrenError::SegmentMismatch {
+                expected: 1,
+                found: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn words_are_text() {
+        for value in ["Hello", "Total (net)", "東京", "A4 paper", "3 apples"] {
+            assert!(is_text(value), "{value}");
+        }
+    }
+
+    #[test]
+    fn numbers_dates_and_booleans_are_not_text() {
+        for value in [
+            "",
+            "  ",
+            "42",
+            "-3.5",
+            "1e5",
+            "=SUM(A1:A3)",
+            "TRUE",
+            "false",
+            "2024-01-05",
+            "2024-01-05T09:30:00Z",
+            "09:30",
+            "05/01/2024",
+            "12%",
+            "-0.5 %",
+            "$1,234.50",
+            "€12",
+            "12,50 €",
+            "(£3.00)",
+            "USD 12.50",
+            "12.50 EUR",
+        ] {
+            assert!(!is_text(value), "{value}");
+        }
+    }
+}
@@ -0,0 +1,610 @@
+use crate::chunk::{AST, TOK_SEP, TaskType, Tasks};
+use crate::error::{Result, TrenError};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::Path;
+
+/// Cues given as context on each side of a cue.
+const CONTEXT_CUES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Srt,
+    Vtt,
+    Ass,
+}
+
+impl Format {
+    /// Delimiters of styling tags; SRT players read the tags of both others.
+    fn tags(self) -> &'static [(char, char)] {
+        match self {
+            Format::Srt => &[('<', '>'), ('{', '}')],
+            Format::Vtt => &[('<', '>')],
+            Format::Ass => &[('{', '}')],
+        }
+    }
+}
+
+/// The text of a cue split around its styling tags.
+struct Cue {
+    /// Where the text sits in the file.
+    range: Range<usize>,
+    /// Tags before the first words.
+    lead: String,
+    /// Text between tags, each with the tags that follow it. Line breaks are
+    /// kept, unless lines are re-wrapped: then they are spaces, except before
+    /// the dash of another speaker.
+    segments: Vec<(String, String)>,
+    /// The `\N` or `\n` between the lines of an ASS cue, when it has more
+    /// than one.
+    line_break: Option<&'static str>,
+}
+
+impl Cue {
+    fn text(&self) -> String {
+        self.segments
+            .iter()
+            .map(|(text, _)| text.as_str())
+            .collect::<Vec<_>>()
+            .join(&TOK_SEP.to_string())
+    }
+}
+
+/// A subtitle file translated cue by cue: each cue is a chunk with the cues
+/// around it as context, and only cue text is rewritten, so numbering,
+/// timestamps, positions and styles stay as they were.
+pub struct SubtitleAST {
+    format: Format,
+    max_line_chars: Option<usize>,
+    max_lines: Option<usize>,
+    source: String,
+    /// What breaks the lines of a cue without breaks of its own: the newline
+    /// of the file, or the form most ASS cues use.
+    line_break: &'static str,
+    cues: Vec<Cue>,
+    /// Translated cue text, wrapped and ready to write.
+    translations: Vec<String>,
+}
+
+impl SubtitleAST {
+    pub fn with_format(format: Format) -> Self {
+        SubtitleAST {
+            format,
+            max_line_chars: None,
+            max_lines: None,
+            source: String::new(),
+            line_break: "\n",
+            cues: vec![],
+            translations: vec![],
+        }
+    }
+
+    pub fn with_wrap(
+        self,
+        max_line_chars: Option<NonZeroUsize>,
+        max_lines: Option<NonZeroUsize>,
+    ) -> Self {
+        SubtitleAST {
+            max_line_chars: max_line_chars.map(NonZeroUsize::get),
+            max_lines: max_lines.map(NonZeroUsize::get),
+            ..self
+        }
+    }
+
+    /// Where the text of each cue sits: the lines after a timing line, up to
+    /// a blank line.
+    fn srt_cues(&self) -> Vec<Range<usize>> {
+        let mut cues = vec![];
+        let mut cue: Option<Range<usize>> = None;
+        let mut timing = false;
+        let mut offset = 0;
+        for line in self.source.split_inclusive('\n') {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let start = offset;
+            offset += line.len();
+            if content.trim().is_empty() {
+                cues.extend(cue.take());
+                timing = false;
+            } else if timing {
+                match cue.as_mut() {
+                    Some(cue) => cue.end = start + content.len(),
+                    None => cue = Some(start..start + content.len()),
+                }
+            } else if content.contains("-->") {
+                timing = true;
+            }
+        }
+        cues.extend(cue);
+        cues
+    }
+
+    /// Where the text of each `Dialogue` line of the `[Events]` section sits:
+    /// after as many commas as the fields before `Text`.
+    fn ass_cues(&self) -> Vec<Range<usize>> {
+        let mut cues = vec![];
+        let mut events = false;
+        let mut fields = 10;
+        let mut offset = 0;
+        for line in self.source.split_inclusive('\n') {
+            let content = line.trim_end_matches(['\r', '\n']);
+            let start = offset;
+            offset += line.len();
+            if content.starts_with('[') {
+                events = content.trim().eq_ignore_ascii_case("[events]");
+            } else if !events {
+                continue;
+            } else if let Some(format) = content.strip_prefix("Format:") {
+                fields = format.split(',').count();
+            } else if let Some(dialogue) = content.strip_prefix("Dialogue:") {
+                let before = dialogue
+                    .match_indices(',')
+                    .nth(fields.saturating_sub(2))
+                    .map(|(comma, _)| comma + 1);
+                if let Some(before) = before {
+                    let text = start + "Dialogue:".len() + before;
+                    cues.push(text..start + content.len());
+                }
+            }
+        }
+        cues
+    }
+
+    /// The cue's lines, by the line breaks of the format.
+    fn lines<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        match self.format {
+            Format::Ass => text
+                .split("\\N")
+                .flat_map(|line| line.split("\\n"))
+                .collect(),
+            _ => text.lines().collect(),
+        }
+    }
+
+    fn cue(&self, range: Range<usize>) -> Cue {
+        let tags = self.format.tags();
+        let source = &self.source[range.clone()];
+        let lines = self.lines(source);
+        let mut text = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                let speaker = plain(line, tags).trim_start().starts_with('-');
+                let wrapped = self.max_line_chars.is_some();
+                text.push(if speaker || !wrapped { '\n' } else { ' ' });
+            }
+            text.push_str(line);
+        }
+
+        let mut lead = String::new();
+        let mut segments: Vec<(String, String)> = vec![];
+        for (piece, tag) in pieces(&text, tags) {
+            let tags = match segments.last_mut() {
+                Some((_, tags)) => tags,
+                None => &mut lead,
+            };
+            // spaces between tags stay with them
+            if tag || piece.trim().is_empty() {
+                tags.push_str(piece);
+            } else {
+                segments.push((piece.to_string(), String::new()));
+            }
+        }
+        let line_break = match self.format {
+            Format::Ass => ["\\N", "\\n"]
+                .into_iter()
+                .filter_map(|line_break| Some((source.find(line_break)?, line_break)))
+                .min()
+                .map(|(_, line_break)| line_break),
+            _ => None,
+        };
+        Cue {
+            range,
+            lead,
+            segments,
+            line_break,
+        }
+    }
+
+    /// The translation with the tags of the cue put back, on the lines the
+    /// translation has or re-wrapped to `max_line_chars`.
+    fn layout(&self, cue: &Cue, translation: &str) -> String {
+        let mut text = cue.lead.clone();
+        for (segment, (_, tags)) in translation.split(TOK_SEP).zip(&cue.segments) {
+            text.push_str(segment);
+            text.push_str(tags);
+        }
+        let line_break = cue.line_break.unwrap_or(self.line_break);
+        // a blank line would end the cue
+        let lines = text
+            .split('\n')
+            .map(str::trim_end)
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>();
+        let Some(width) = self.max_line_chars else {
+            return lines.join(line_break);
+        };
+        let tags = self.format.tags();
+        let lines = match lines[..] {
+            [line] => {
+                let words = words(line, tags, width);
+                let lines = fill(&words, width);
+                match self.max_lines {
+                    Some(max_lines) if lines.len() > max_lines => balance(&words, max_lines),
+                    _ => lines,
+                }
+            }
+            // one speaker per line at least; lines cannot be merged
+            ref speakers => speakers
+                .iter()
+                .flat_map(|speaker| fill(&words(speaker, tags, width), width))
+                .collect(),
+        };
+        lines.join(line_break)
+    }
+}
+
+impl AST for SubtitleAST {
+    fn import(&mut self, filepath: &Path) -> Result<()> {
+        self.source = std::fs::read_to_string(filepath)?;
+        self.line_break = if self.source.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let ranges = match self.format {
+            Format::Srt | Format::Vtt => self.srt_cues(),
+            Format::Ass => self.ass_cues(),
+        };
+        self.cues = ranges
+            .into_iter()
+            .map(|range| self.cue(range))
+            .filter(|cue| !cue.segments.is_empty())
+            .collect();
+        if self.format == Format::Ass {
+            let cues = |line_break| {
+                let breaking = |cue: &&Cue| cue.line_break == Some(line_break);
+                self.cues.iter().filter(breaking).count()
+            };
+            self.line_break = if cues("\\n") > cues("\\N") {
+                "\\n"
+            } else {
+                "\\N"
+            };
+        }
+        if self.cues.is_empty() {
+            return Err(TrenError::Unsupported {
+                node: format!("no {:?} cues", self.format),
+                location: filepath.display().to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn export(&self, filepath: &Path) -> Result<()> {
+        let mut text = self.source.clone();
+        for (cue, translation) in self.cues.iter().zip(&self.translations).rev() {
+            text.replace_range(cue.range.clone(), translation);
+        }
+        std::fs::write(filepath, text)?;
+        Ok(())
+    }
+
+    fn to_mipcs(&self) -> Tasks {
+        let mut tasks = Tasks::new();
+        let texts = self.cues.iter().map(Cue::text).collect::<Vec<_>>();
+        for (i, text) in texts.iter().enumerate() {
+            tasks.add(text.clone(), TaskType::Main);
+            let plain = |cues: &[String]| {
+                cues.iter()
+                    .map(|cue| cue.replace(TOK_SEP, ""))
+                    .collect::<Vec<_>>()
+            };
+            let after = (i + 1 + CONTEXT_CUES).min(texts.len());
+            tasks.context.insert(
+                (TaskType::Main, i),
+                plain(&texts[i.saturating_sub(CONTEXT_CUES)..i]),
+            );
+            tasks
+                .next
+                .insert((TaskType::Main, i), plain(&texts[i + 1..after]));
+        }
+        tasks
+    }
+
+    fn apply_mipcs(&mut self, mut mipcs: Tasks) -> Result<()> {
+        let mut translations = vec![];
+        for cue in &self.cues {
+            let (index, translation) = mipcs.collect(TaskType::Main)?;
+            let expected = cue.segments.len();
+            let found = translation.split(TOK_SEP).count();
+            if found != expected {
+                return Err(TrenError::SegmentMismatch {
+                    index,
+                    task_type: TaskType::Main,
+                    expected,
+                    found,
+                });
+            }
+            translations.push(self.layout(cue, &translation));
+        }
+        self.translations = translations;
+        Ok(())
+    }
+}
+
+/// The text split into styling tags and the text between them, each with
+/// whether it is a tag.
+fn pieces<'a>(text: &'a str, tags: &[(char, char)]) -> Vec<(&'a str, bool)> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    let mut rest = 0;
+    while let Some(offset) = text[rest..].find(|c| tags.iter().any(|(open, _)| *open == c)) {
+        let open = rest + offset;
+        let opener = text[open..].chars().next().unwrap_or_default();
+        let closer = tags
+            .iter()
+            .find(|(open, _)| *open == opener)
+            .map_or(opener, |(_, close)| *close);
+        let Some(close) = text[open..].find(closer).map(|close| open + close + 1) else {
+            break;
+        };
+        if open > start {
+            pieces.push((&text[start..open], false));
+        }
+        pieces.push((&text[open..close], true));
+        start = close;
+        rest = close;
+    }
+    if start < text.len() {
+        pieces.push((&text[start..], false));
+    }
+    pieces
+}
+
+/// The text without its styling tags.
+fn plain(text: &str, tags: &[(char, char)]) -> String {
+    pieces(text, tags)
+        .into_iter()
+        .filter(|(_, tag)| !tag)
+        .map(|(piece, _)| piece)
+        .collect()
+}
+
+/// A word to lay out: its text with any tags inside it, its width on screen,
+/// and whether it joins the word before without a space.
+type Word = (String, usize, bool);
+
+/// Words split at whitespace outside tags. A word wider than `width`, such as
+/// a line of Chinese or Thai, which have no spaces, is split into its
+/// characters, which may break anywhere.
+fn words(text: &str, tags: &[(char, char)], width: usize) -> Vec<Word> {
+    let mut words = vec![];
+    // the characters of the word being read, each with the tags before it
+    let mut chars: Vec<String> = vec![];
+    let mut pending = String::new();
+    let mut end_word = |chars: &mut Vec<String>, pending: &mut String| {
+        if let Some(last) = chars.last_mut() {
+            last.push_str(&std::mem::take(pending));
+        }
+        if chars.len() > width {
+            words.extend(chars.drain(..).enumerate().map(|(i, c)| (c, 1, i > 0)));
+        } else if !chars.is_empty() || !pending.is_empty() {
+            let len = chars.len();
+            words.push((chars.drain(..).collect::<String>() + pending, len, false));
+        }
+        pending.clear();
+    };
+    for (piece, tag) in pieces(text, tags) {
+        if tag {
+            pending.push_str(piece);
+            continue;
+        }
+        for c in piece.chars() {
+            if c.is_whitespace() {
+                end_word(&mut chars, &mut pending);
+            } else {
+                chars.push(std::mem::take(&mut pending) + c.encode_utf8(&mut [0; 4]));
+            }
+        }
+    }
+    end_word(&mut chars, &mut pending);
+    words
+}
+
+/// Lines of at most `width` characters, but for words longer than that.
+fn fill(words: &[Word], width: usize) -> Vec<String> {
+    let mut lines: Vec<(String, usize)> = vec![];
+    for (word, len, glued) in words {
+        let space = usize::from(!glued);
+        match lines.last_mut() {
+            Some((line, used)) if *used + space + len <= width => {
+                if !glued {
+                    line.push(' ');
+                }
+                line.push_str(word);
+                *used += space + len;
+            }
+            _ => lines.push((word.clone(), *len)),
+        }
+    }
+    lines.into_iter().map(|(line, _)| line).collect()
+}
+
+/// At most `count` lines as even in length as they can be.
+fn balance(words: &[Word], count: usize) -> Vec<String> {
+    let total = words
+        .iter()
+        .map(|(_, len, glued)| len + usize::from(!glued))
+        .sum::<usize>();
+    let count = count.max(1);
+    (total.div_ceil(count)..total)
+        .map(|width| fill(words, width))
+        .find(|lines| lines.len() <= count)
+        .unwrap_or_else(|| fill(words, usize::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRT: &str = "1\r\n00:00:01,000 --> 00:00:02,000\r\n{\\an8}<i>Hello there,</i>\r\nmy friend.\r\n\r\n2\r\n00:00:03,000 --> 00:00:04,000\r\n- Who are you?\r\n- Nobody.\r\n\r\n3\r\n00:00:05,000 --> 00:00:06,000\r\nBye\r\n";
+    const VTT: &str = "WEBVTT\n\nNOTE not a cue\n\nintro\n00:00:01.000 --> 00:00:02.000 line:0\n<v Bob>Good <b>morning</b></v>\n\n00:00:03.000 --> 00:00:04.000\nSecond cue\n";
+    const ASS: &str = "[Script Info]\nTitle: Dialogue: not a cue\n\n[Events]\nFormat: Layer, Start, End, Style, Text\nDialogue: 0,0:00:01.00,0:00:02.00,Default,{\\pos(10,20)}Hello, world\\NSecond line\nComment: 0,0:00:01.00,0:00:02.00,Default,not me\nDialogue: 0,0:00:03.00,0:00:04.00,Default,Bye\n";
+
+    fn subtitle(format: Format, wrap: Option<usize>, source: &str) -> SubtitleAST {
+        let path = std::env::temp_dir().join(format!("tren-{}.sub", uuid::Uuid::now_v7()));
+        std::fs::write(&path, source).unwrap();
+        let mut ast = SubtitleAST::with_format(format)
+            .with_wrap(wrap.and_then(NonZeroUsize::new), NonZeroUsize::new(2));
+        ast.import(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        ast
+    }
+
+    fn texts(ast: &SubtitleAST) -> Vec<&str> {
+        ast.cues
+            .iter()
+            .map(|cue| &ast.source[cue.range.clone()])
+            .collect()
+    }
+
+    fn export(mut ast: SubtitleAST, f: impl Fn(&str) -> String) -> String {
+        let tasks = ast.to_mipcs().translated(f);
+        ast.apply_mipcs(tasks).unwrap();
+        let path = std::env::temp_dir().join(format!("tren-{}.out", uuid::Uuid::now_v7()));
+        ast.export(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        text
+    }
+
+    #[test]
+    fn srt_cues_are_the_lines_after_their_timing() {
+        let ast = subtitle(Format::Srt, None, SRT);
+        assert_eq!(
+            texts(&ast),
+            [
+                "{\\an8}<i>Hello there,</i>\r\nmy friend.",
+                "- Who are you?\r\n- Nobody.",
+                "Bye"
+            ]
+        );
+        let tasks = ast.to_mipcs();
+        assert_eq!(
+            tasks.main,
+            [
+                "Hello there,𐑙\nmy friend.",
+                "- Who are you?\n- Nobody.",
+                "Bye"
+            ]
+        );
+        assert_eq!(
+            tasks.context[&(TaskType::Main, 1)],
+            ["Hello there,\nmy friend."]
+        );
+        assert_eq!(tasks.next[&(TaskType::Main, 1)], ["Bye"]);
+    }
+
+    #[test]
+    fn vtt_cues_skip_notes_and_keep_settings() {
+        let ast = subtitle(Format::Vtt, None, VTT);
+        assert_eq!(
+            texts(&ast),
+            ["<v Bob>Good <b>morning</b></v>", "Second cue"]
+        );
+        assert_eq!(ast.to_mipcs().main, ["Good 𐑙morning", "Second cue"]);
+        assert_eq!(
+            export(ast, str::to_uppercase),
+            VTT.replace("Good <b>morning", "GOOD <b>MORNING")
+                .replace("Second cue", "SECOND CUE")
+        );
+    }
+
+    #[test]
+    fn ass_cues_are_the_text_field_of_dialogue_events() {
+        let ast = subtitle(Format::Ass, None, ASS);
+        assert_eq!(
+            texts(&ast),
+            ["{\\pos(10,20)}Hello, world\\NSecond line", "Bye"]
+        );
+        assert_eq!(ast.to_mipcs().main, ["Hello, world\nSecond line", "Bye"]);
+        assert_eq!(
+            export(ast, str::to_uppercase),
+            ASS.replace("Hello, world\\NSecond line", "HELLO, WORLD\\NSECOND LINE")
+                .replace(",Bye", ",BYE")
+        );
+    }
+
+    #[test]
+    fn ass_cues_keep_their_line_breaks() {
+        let ass = ASS.replace("world\\NSecond", "world\\nSecond").replace(
+            ",Bye",
+            ",Bye\\NBye\nDialogue: 0,0:00:05.00,0:00:06.00,Default,Good night, Tom",
+        );
+        let exported = export(subtitle(Format::Ass, None, &ass), |chunk| {
+            chunk.to_uppercase()
+        });
+        assert!(exported.contains("}HELLO, WORLD\\nSECOND LINE\n"));
+        assert!(exported.contains(",BYE\\NBYE\n"));
+
+        // cues without breaks take the form most cues use
+        let ass = ass.replace(",Bye\\NBye", ",Bye\\nBye");
+        let exported = export(subtitle(Format::Ass, Some(10), &ass), |chunk| {
+            chunk.replace("Good night, Tom", "Bonne nuit, Tom")
+        });
+        assert!(exported.contains(",Bonne\\nnuit, Tom\n"));
+    }
+
+    #[test]
+    fn translations_keep_their_lines_and_tags() {
+        let srt = export(subtitle(Format::Srt, None, SRT), |chunk| {
+            chunk
+                .replace("Hello there,", "Salut,")
+                .replace("\nmy friend.", "\nmon ami.\n\n")
+        });
+        assert!(srt.contains("{\\an8}<i>Salut,</i>\r\nmon ami.\r\n\r\n2\r\n"));
+    }
+
+    #[test]
+    fn wrapping_joins_lines_but_keeps_speakers_apart() {
+        let ast = subtitle(Format::Srt, Some(14), SRT);
+        assert_eq!(
+            ast.to_mipcs().main,
+            [
+                "Hello there,𐑙 my friend.",
+                "- Who are you?\n- Nobody.",
+                "Bye"
+            ]
+        );
+        let srt = export(ast, |chunk| chunk.to_string());
+        assert!(srt.contains("{\\an8}<i>Hello there,</i>\r\nmy friend.\r\n"));
+        assert!(srt.contains("- Who are you?\r\n- Nobody.\r\n"));
+    }
+
+    #[test]
+    fn fill_and_balance_lines() {
+        let words = words("one two three four five", &[], 42);
+        assert_eq!(fill(&words, 9), ["one two", "three", "four five"]);
+        assert_eq!(balance(&words, 2), ["one two three", "four five"]);
+        assert_eq!(balance(&words, 1), ["one two three four five"]);
+    }
+
+    #[test]
+    fn tags_take_no_width() {
+        let words = words("<i>one</i> two", Format::Srt.tags(), 42);
+        assert_eq!(
+            words,
+            [
+                ("<i>one</i>".to_string(), 3, false),
+                ("two".to_string(), 3, false)
+            ]
+        );
+        assert_eq!(fill(&words, 7), ["<i>one</i> two"]);
+    }
+
+    #[test]
+    fn long_words_break_between_characters() {
+        let words = words("<i>这是一个很长的句子</i>", Format::Srt.tags(), 4);
+        assert_eq!(fill(&words, 4), ["<i>这是一个", "很长的句", "子</i>"]);
+        assert_eq!(balance(&words, 2), ["<i>这是一个很", "长的句子</i>"]);
+    }
+}
@@ -0,0 +1,532 @@
+use crate::chunk::TOK_SEP;
+use crate::chunk::ooxml::{
+    INLINE_STRING, Namespaces, Package, SPREADSHEET_ML, XmlPart, corrupt, unescape,
+};
+use crate::chunk::spreadsheet::{Book, Cell, Sheet, cell_ref};
+use crate::chunk::{TaskType, Tasks};
+use crate::error::{Result, TrenError};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+const WORKBOOK: &str = "xl/workbook.xml";
+const SHARED_STRINGS: &str = "/sharedStrings";
+
+/// Where the translation of an Excel cell goes.
+pub enum XlsxKey {
+    /// A cell pointing to a shared string: its `<v>` index is pointed to a new
+    /// string, so other cells sharing the old one keep it. Cells with the same
+    /// translation share the new string.
+    Shared {
+        sheet: usize,
+        value: Range<usize>,
+        string: usize,
+    },
+    /// A cell holding its string in an `<is>` element, replaced as a whole.
+    Inline {
+        sheet: usize,
+        element: Range<usize>,
+        string: XmlPart,
+    },
+}
+
+/// An Excel workbook; only the strings of cells change, so styles, formulas,
+/// charts and everything else in the package stay as they were.
+pub struct Xlsx {
+    package: Package,
+    /// Names and text of the worksheet parts, in sheet order.
+    parts: Vec<(String, String)>,
+    /// Name and text of the shared strings part, with each of its strings.
+    shared: Option<(String, String, Vec<XmlPart>)>,
+    sheets: Vec<Sheet<XlsxKey>>,
+}
+
+impl Book for Xlsx {
+    type Key = XlsxKey;
+
+    fn read(filepath: &Path) -> Result<Self> {
+        let package = Package::read(filepath)?;
+        let location = filepath.display().to_string();
+        let workbook = package.part(WORKBOOK)?.ok_or(TrenError::Unsupported {
+            node: format!("no {WORKBOOK}"),
+            location: location.clone(),
+        })?;
+        let targets = package.relationships(WORKBOOK)?;
+
+        let shared = match targets
+            .iter()
+            .find(|(_, kind, _)| kind.ends_with(SHARED_STRINGS))
+        {
+            Some((_, _, name)) => match package.part(name)? {
+                Some(xml) => {
+                    let strings = shared_strings(name, &xml)?;
+                    Some((name.clone(), xml, strings))
+                }
+                None => None,
+            },
+            None => None,
+        };
+
+        let mut parts = vec![];
+        let mut sheets = vec![];
+        for (name, id) in sheet_ids(&workbook, &location)? {
+            let Some((_, _, part)) = targets.iter().find(|(rel_id, _, _)| *rel_id == id) else {
+                continue;
+            };
+            let Some(xml) = package.part(part)? else {
+                continue;
+            };
+            let strings = shared.as_ref().map_or(&[][..], |(_, _, strings)| strings);
+            // chartsheets and dialog sheets have no cells
+            let Some(cells) = cells(sheets.len(), part, &xml, strings)? else {
+                continue;
+            };
+            sheets.push(Sheet { name, cells });
+            parts.push((part.clone(), xml));
+        }
+
+        Ok(Xlsx {
+            package,
+            parts,
+            shared,
+            sheets,
+        })
+    }
+
+    fn sheets(&self) -> &[Sheet<Self::Key>] {
+        &self.sheets
+    }
+
+    fn write(&self, filepath: &Path, translations: &[(&Self::Key, &str)]) -> Result<()> {
+        let apply = |string: &XmlPart, translation: &str| {
+            string.apply(
+                &mut Tasks::with_chunks([translation.to_string()].into(), [].into()),
+                TaskType::Main,
+            )
+        };
+
+        let mut edits: Vec<Vec<(Range<usize>, String)>> = vec![vec![]; self.parts.len()];
+        let mut added = vec![];
+        // cells translated alike share one new string
+        let mut indices = HashMap::new();
+        for (key, translation) in translations {
+            match key {
+                XlsxKey::Shared {
+                    sheet,
+                    value,
+                    string,
+                } => {
+                    let Some((_, _, strings)) = &self.shared else {
+                        continue;
+                    };
+                    let si = apply(&strings[*string], translation)?;
+                    let index = *indices.entry(si.clone()).or_insert_with(|| {
+                        added.push(si);
+                        strings.len() + added.len() - 1
+                    });
+                    edits[*sheet].push((value.clone(), index.to_string()));
+                }
+                XlsxKey::Inline {
+                    sheet,
+                    element,
+                    string,
+                } => edits[*sheet].push((element.clone(), apply(string, translation)?)),
+            }
+        }
+
+        let mut replaced = HashMap::new();
+        for ((name, xml), mut edits) in self.parts.iter().zip(edits) {
+            if edits.is_empty() {
+                continue;
+            }
+            edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+            let mut xml = xml.clone();
+            for (range, text) in edits {
+                xml.replace_range(range, &text);
+            }
+            replaced.insert(name.clone(), xml);
+        }
+        if let Some((name, xml, strings)) = &self.shared
+            && !added.is_empty()
+        {
+            replaced.insert(name.clone(), add_strings(name, xml, strings.len(), &added)?);
+        }
+        self.package.write(filepath, &replaced)
+    }
+}
+
+/// Names and relationship ids of the sheets listed in the workbook.
+fn sheet_ids(workbook: &str, location: &str) -> Result<Vec<(String, String)>> {
+    let namespaces = Namespaces::read(location, workbook)?;
+    let mut sheets = vec![];
+    let mut current: Option<(String, String)> = None;
+    for token in Tokenizer::from(workbook) {
+        match token.map_err(|err| corrupt(location, err))? {
+            Token::ElementStart { prefix, local, .. } => {
+                let element = namespaces.name(prefix.as_str(), local.as_str());
+                current = (element == "sheet").then(Default::default);
+            }
+            Token::Attribute {
+                prefix,
+                local,
+                value,
+                ..
+            } => {
+                if let Some((name, id)) = current.as_mut() {
+                    match (prefix.is_empty(), local.as_str()) {
+                        (true, "name") => *name = unescape(value.as_str()),
+                        (false, "id") => *id = value.to_string(),
+                        _ => {}
+                    }
+                }
+            }
+            Token::ElementEnd { .. } => sheets.extend(current.take()),
+            _ => {}
+        }
+    }
+    Ok(sheets)
+}
+
+/// Each `<si>` of the shared strings part.
+fn shared_strings(name: &str, xml: &str) -> Result<Vec<XmlPart>> {
+    let namespaces = Namespaces::read(name, xml)?;
+    let mut strings = vec![];
+    let mut element = String::new();
+    let mut start = 0;
+    for token in Tokenizer::from(xml) {
+        match token.map_err(|err| corrupt(name, err))? {
+            Token::ElementStart {
+                prefix,
+                local,
+                span,
+            } => {
+                element = namespaces.name(prefix.as_str(), local.as_str());
+                if element == "si" {
+                    start = span.start();
+                }
+            }
+            Token::ElementEnd { end, span } => {
+                let closed = match end {
+                    ElementEnd::Open => false,
+                    ElementEnd::Empty => element == "si",
+                    ElementEnd::Close(prefix, local) => {
+                        namespaces.name(prefix.as_str(), local.as_str()) == "si"
+                    }
+                };
+                if closed {
+                    strings.push(XmlPart::parse_fragment(
+                        name,
+                        xml[start..span.end()].to_string(),
+                        &SPREADSHEET_ML,
+                        &namespaces,
+                    )?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(strings)
+}
+
+/// The shared strings part with strings appended and its unique count updated.
+fn add_strings(name: &str, xml: &str, count: usize, added: &[String]) -> Result<String> {
+    let end = xml.rfind("</").ok_or_else(|| corrupt(name, "no end tag"))?;
+    let mut xml = format!("{}{}{}", &xml[..end], added.concat(), &xml[end..]);
+    let total = count + added.len();
+    if let Some(start) = xml.find("uniqueCount=\"") {
+        let value = start + "uniqueCount=\"".len();
+        if let Some(len) = xml[value..].find('"') {
+            xml.replace_range(value..value + len, &total.to_string());
+        }
+    }
+    Ok(xml)
+}
+
+/// A `<c>` element being read.
+#[derive(Default)]
+struct RawCell {
+    row: usize,
+    column: usize,
+    kind: String,
+    formula: bool,
+    value: Option<Range<usize>>,
+    inline: Option<Range<usize>>,
+}
+
+/// The cells of a worksheet part, or `None` for a part without `<sheetData>`.
+fn cells(
+    sheet: usize,
+    name: &str,
+    xml: &str,
+    strings: &[XmlPart],
+) -> Result<Option<Vec<Cell<XlsxKey>>>> {
+    let namespaces = Namespaces::read(name, xml)?;
+    let mut sheet_data = false;
+    let mut cells = vec![];
+    let mut element = String::new();
+    let (mut row, mut next_row, mut next_column) = (0, 0, 0);
+    let mut cell: Option<RawCell> = None;
+    let mut value_start = 0;
+    let mut inline_start = 0;
+
+    for token in Tokenizer::from(xml) {
+        match token.map_err(|err| corrupt(name, err))? {
+            Token::ElementStart {
+                prefix,
+                local,
+                span,
+            } => {
+                element = namespaces.name(prefix.as_str(), local.as_str());
+                match element.as_str() {
+                    "sheetData" => sheet_data = true,
+                    "row" => {
+                        row = next_row;
+                        next_column = 0;
+                    }
+                    "c" => {
+                        cell = Some(RawCell {
+                            row,
+                            column: next_column,
+                            ..Default::default()
+                        })
+                    }
+                    "f" => {
+                        if let Some(cell) = cell.as_mut() {
+                            cell.formula = true;
+                        }
+                    }
+                    "is" => inline_start = span.start(),
+                    _ => {}
+                }
+            }
+            Token::Attribute { local, value, .. } => match (element.as_str(), local.as_str()) {
+                ("row", "r") => {
+                    if let Ok(r) = value.as_str().parse::<usize>() {
+                        row = r.saturating_sub(1);
+                    }
+                }
+                ("c", "r") => {
+                    if let (Some(cell), Some((Some(r), column))) =
+                        (cell.as_mut(), cell_ref(value.as_str()))
+                    {
+                        cell.row = r;
+                        cell.column = column;
+                    }
+                }
+                ("c", "t") => {
+                    if let Some(cell) = cell.as_mut() {
+                        cell.kind = value.to_string();
+                    }
+                }
+                _ => {}
+            },
+            Token::ElementEnd { end, span } => {
+                let closed = match end {
+                    ElementEnd::Close(prefix, local) => {
+                        namespaces.name(prefix.as_str(), local.as_str())
+                    }
+                    _ => element.clone(),
+                };
+                match (closed.as_str(), end) {
+                    ("row", ElementEnd::Open) => next_row = row + 1,
+                    ("row", ElementEnd::Empty) => next_row = row + 1,
+                    ("v", ElementEnd::Open) => value_start = span.end(),
+                    ("v", ElementEnd::Close(..)) => {
+                        if let Some(cell) = cell.as_mut() {
+                            cell.value = Some(value_start..span.start());
+                        }
+                    }
+                    ("is", ElementEnd::Close(..)) => {
+                        if let Some(cell) = cell.as_mut() {
+                            cell.inline = Some(inline_start..span.end());
+                        }
+                    }
+                    ("c", ElementEnd::Empty | ElementEnd::Close(..)) => {
+                        if let Some(raw) = cell.take() {
+                            next_column = raw.column + 1;
+                            cells.push(read_cell(sheet, name, xml, &namespaces, strings, raw)?);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(sheet_data.then_some(cells))
+}
+
+fn read_cell(
+    sheet: usize,
+    name: &str,
+    xml: &str,
+    namespaces: &Namespaces,
+    strings: &[XmlPart],
+    raw: RawCell,
+) -> Result<Cell<XlsxKey>> {
+    let text = match (raw.kind.as_str(), &raw.value, &raw.inline) {
+        ("s", Some(value), _) => {
+            let string = xml[value.clone()]
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|string| *string < strings.len());
+            string.and_then(|string| {
+                let text = strings[string].text()?;
+                Some((
+                    text,
+                    XlsxKey::Shared {
+                        sheet,
+                        value: value.clone(),
+                        string,
+                    },
+                ))
+            })
+        }
+        ("inlineStr", _, Some(element)) => {
+            let string = XmlPart::parse_fragment(
+                name,
+                xml[element.clone()].to_string(),
+                &INLINE_STRING,
+                namespaces,
+            )?;
+            string.text().map(|text| {
+                (
+                    text,
+                    XlsxKey::Inline {
+                        sheet,
+                        element: element.clone(),
+                        string,
+                    },
+                )
+            })
+        }
+        _ => None,
+    };
+    let value = match (&text, &raw.value) {
+        (Some((text, _)), _) => text.replace(TOK_SEP, ""),
+        // the index of a blank string
+        _ if raw.kind == "s" => String::new(),
+        (None, Some(value)) => unescape(&xml[value.clone()]),
+        (None, None) => String::new(),
+    };
+    Ok(Cell {
+        row: raw.row,
+        column: raw.column,
+        value,
+        // a formula keeps computing its own string
+        text: text.filter(|_| !raw.formula),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ooxml::tests::write_package;
+
+    const NS: &str = r#"xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships""#;
+    const RELS: &str = r#"<Relationships><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/><Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/chartsheet" Target="chartsheets/sheet1.xml"/><Relationship Id="rId3" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/sharedStrings" Target="sharedStrings.xml"/></Relationships>"#;
+
+    fn xlsx() -> std::path::PathBuf {
+        write_package(
+            "in.xlsx",
+            &[
+                (
+                    WORKBOOK,
+                    &format!(
+                        r#"<workbook {NS}><sheets><sheet name="Items &amp; more" sheetId="1" r:id="rId1"/><sheet name="Chart" sheetId="2" r:id="rId2"/></sheets></workbook>"#
+                    ),
+                ),
+                ("xl/_rels/workbook.xml.rels", RELS),
+                (
+                    "xl/sharedStrings.xml",
+                    &format!(
+                        r#"<sst {NS} count="4" uniqueCount="2"><si><t>Apple</t></si><si><r><rPr><b/></rPr><t>Red</t></r><r><t xml:space="preserve"> fruit</t></r></si></sst>"#
+                    ),
+                ),
+                (
+                    "xl/worksheets/sheet1.xml",
+                    &format!(
+                        r#"<worksheet {NS}><sheetData><row r="1"><c r="A1" t="s"><v>0</v></c><c r="B1" t="s"><v>1</v></c><c r="C1"><v>3</v></c></row><row r="3"><c r="A3" t="s"><v>0</v></c><c r="B3" t="inlineStr"><is><t>Green</t></is></c><c r="C3" t="str"><f>A3&amp;"!"</f><v>Apple!</v></c><c r="D3" t="s"><v>0</v></c></row></sheetData></worksheet>"#
+                    ),
+                ),
+                ("xl/chartsheets/sheet1.xml", &format!("<chartsheet {NS}/>")),
+            ],
+        )
+    }
+
+    #[test]
+    fn cells_are_read_with_their_strings() {
+        let book = Xlsx::read(&xlsx()).unwrap();
+        let [sheet] = book.sheets() else {
+            panic!("the chartsheet has no cells");
+        };
+        assert_eq!(sheet.name, "Items & more");
+        let cells = sheet
+            .cells
+            .iter()
+            .map(|cell| {
+                (
+                    cell.row,
+                    cell.column,
+                    cell.value.as_str(),
+                    cell.text.as_ref().map(|(text, _)| text.as_str()),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cells,
+            [
+                (0, 0, "Apple", Some("Apple")),
+                (0, 1, "Red fruit", Some("Red𐑙 fruit")),
+                (0, 2, "3", None),
+                (2, 0, "Apple", Some("Apple")),
+                (2, 1, "Green", Some("Green")),
+                (2, 2, "Apple!", None),
+                (2, 3, "Apple", Some("Apple")),
+            ]
+        );
+    }
+
+    #[test]
+    fn translations_are_appended_as_new_shared_strings() {
+        let input = xlsx();
+        let book = Xlsx::read(&input).unwrap();
+        let cells = &book.sheets()[0].cells;
+        let key = |i: usize| &cells[i].text.as_ref().unwrap().1;
+        // D3 keeps the original string it shares with the others
+        let translations = [
+            (key(0), "Pomme"),
+            (key(1), "Fruit𐑙 rouge"),
+            (key(3), "Pomme"),
+            (key(4), "Vert"),
+        ];
+        let output = input.with_file_name("out.xlsx");
+        book.write(&output, &translations).unwrap();
+
+        let package = Package::read(&output).unwrap();
+        let strings = package.part("xl/sharedStrings.xml").unwrap().unwrap();
+        assert!(strings.contains(r#"uniqueCount="4""#));
+        assert!(strings.ends_with(r#"<si><t>Apple</t></si><si><r><rPr><b/></rPr><t>Red</t></r><r><t xml:space="preserve"> fruit</t></r></si><si><t>Pomme</t></si><si><r><rPr><b/></rPr><t>Fruit</t></r><r><t xml:space="preserve"> rouge</t></r></si></sst>"#));
+        let sheet = package.part("xl/worksheets/sheet1.xml").unwrap().unwrap();
+        assert!(sheet.contains(r#"<c r="A1" t="s"><v>2</v></c><c r="B1" t="s"><v>3</v></c>"#));
+        assert!(sheet.contains(
+            r#"<c r="A3" t="s"><v>2</v></c><c r="B3" t="inlineStr"><is><t>Vert</t></is></c>"#
+        ));
+        assert!(sheet.contains(r#"<c r="D3" t="s"><v>0</v></c>"#));
+    }
+
+    #[test]
+    fn unique_count_is_updated() {
+        let xml = r#"<sst count="1" uniqueCount="1"><si><t>a</t></si></sst>"#;
+        let added = [
+            "<si><t>b</t></si>".to_string(),
+            "<si><t>c</t></si>".to_string(),
+        ];
+        assert_eq!(
+            add_strings("sst", xml, 1, &added).unwrap(),
+            r#"<sst count="1" uniqueCount="3"><si><t>a</t></si><si><t>b</t></si><si><t>c</t></si></sst>"#
+        );
+    }
+}
@@ -1,8 +1,13 @@
-use clap::{Parser, Subcommand};
-use dotenv;
+use crate::error::{Result, TrenError};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::net::IpAddr;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 pub struct CLI {
@@ -10,12 +15,20 @@ pub struct CLI {
     pub mode: CLIMode,
 }
 
+// parsed once; boxing the arguments buys nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum CLIMode {
     /// Translate a single file on CLI
     Run(JobCLIArgs),
+    /// Rebuild the output from an edited intermediate sheet, without the LLM
+    Apply(ApplyCLIArgs),
     /// Start a web server; can submit translation jobs via web UI
     Web(WebCLIArgs),
+    /// Manage web server accounts used with `tren web --auth`
+    User(UserCLIArgs),
+    /// List models available from the LLM provider
+    Models(ModelsCLIArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -40,10 +53,16 @@ pub struct JobCLIArgs {
     #[arg(short, long)]
     output: Option<PathBuf>,
 
+    #[command(flatten)]
+    document: DocumentArgs,
+
     /// LLM model name; should be a Huggingface repo name.
     #[arg(long, default_value = "openai/gpt-oss-20b")]
     model: String,
 
+    #[command(flatten)]
+    llm: LLMArgs,
+
     /// System prompt for LLM.
     #[arg(long)]
     system: Option<String>,
@@ -55,6 +74,118 @@ pub struct JobCLIArgs {
     /// Maximum parallel request to LLM.
     #[arg(short = 'j', long, default_value = "1")]
     parallel: usize,
+
+    /// Times to resend a request that failed to reach the LLM (connection
+    /// errors, 408, 429 and 5xx responses).
+    #[arg(long, default_value_t = RetryPolicy::default().transport_retries)]
+    transport_retries: u8,
+
+    /// Times to ask again when an answer loses the formatting markers.
+    #[arg(long, default_value_t = RetryPolicy::default().validation_retries)]
+    validation_retries: u8,
+
+    /// Seconds to wait before the first resend; doubled on every failure.
+    #[arg(long, default_value_t = RetryPolicy::default().retry_delay)]
+    retry_delay: f64,
+
+    /// Longest wait in seconds between resends, unless the server asks for
+    /// more with `Retry-After`.
+    #[arg(long, default_value_t = RetryPolicy::default().max_retry_delay)]
+    max_retry_delay: f64,
+
+    /// What to try, in order, when answers keep losing the formatting markers.
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = RetryPolicy::default().fallbacks)]
+    fallbacks: Vec<Fallback>,
+
+    /// Model used by the `model` fallback.
+    #[arg(long)]
+    fallback_model: Option<String>,
+
+    /// Reuse translations from the intermediate sheet of an earlier run; only
+    /// chunks missing from it or whose source text changed are translated.
+    #[arg(long)]
+    pub resume: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ApplyCLIArgs {
+    /// Input file; the same one the sheet was made from
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Intermediate sheet to apply. [default: <INPUT>-inter.csv]
+    #[arg(long)]
+    pub inter_sheet: Option<PathBuf>,
+
+    /// Output file. [default: <INPUT>-translated]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// The same document settings as the run that made the sheet
+    #[command(flatten)]
+    pub document: DocumentArgs,
+}
+
+/// How a document is read and written. A sheet only applies to a document
+/// read with the same settings as when it was made.
+#[derive(Args, Debug, Clone)]
+pub struct DocumentArgs {
+    /// Input format as a pandoc format name. [default: from the input extension]
+    #[arg(long)]
+    pub from: Option<String>,
+
+    /// Output format as a pandoc format name. [default: from the output extension]
+    #[arg(long)]
+    pub to: Option<String>,
+
+    /// Metadata fields to translate, comma separated.
+    #[arg(long = "meta", value_delimiter = ',', default_values_t = DEFAULT_META_KEYS.map(String::from))]
+    pub meta_keys: Vec<String>,
+
+    /// Also translate the comments of Word documents.
+    #[arg(long)]
+    pub comments: bool,
+
+    /// Spreadsheet sheets to translate, comma separated. [default: all]
+    #[arg(long, value_delimiter = ',')]
+    pub sheets: Vec<String>,
+
+    /// Spreadsheet columns to translate as letters, comma separated. [default: all]
+    #[arg(long, value_delimiter = ',')]
+    pub columns: Vec<String>,
+
+    /// Spreadsheet cell ranges to translate such as `B2:D40` or `Sheet1!B2:B9`, comma separated.
+    #[arg(long = "range", value_delimiter = ',')]
+    pub ranges: Vec<String>,
+
+    /// Spreadsheet columns given as context for the cells of their row, comma separated. [default: the whole row]
+    #[arg(long, value_delimiter = ',')]
+    pub context_columns: Vec<String>,
+
+    /// Most characters on a line of a translated subtitle; lines are re-wrapped only when set. [default: the translation's own lines]
+    #[arg(long)]
+    pub max_line_chars: Option<NonZeroUsize>,
+
+    /// Most lines in a subtitle re-wrapped by `--max-line-chars`. [default: no limit]
+    #[arg(long)]
+    pub max_lines: Option<NonZeroUsize>,
+}
+
+impl Default for DocumentArgs {
+    fn default() -> Self {
+        DocumentArgs {
+            from: None,
+            to: None,
+            meta_keys: DEFAULT_META_KEYS.map(String::from).to_vec(),
+            comments: false,
+            sheets: vec![],
+            columns: vec![],
+            ranges: vec![],
+            context_columns: vec![],
+            max_line_chars: None,
+            max_lines: None,
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -78,6 +209,117 @@ pub struct WebCLIArgs {
     /// Maximum parallel job processing at one time
     #[arg(short = 'j', long, default_value = "1")]
     pub parallel: usize,
+
+    /// Serve the web UI from this directory instead of the one built into the binary
+    #[arg(long)]
+    pub webui: Option<PathBuf>,
+
+    /// Model catalogue (JSON) describing the models offered. [default: <DATA>/models.json]
+    #[arg(long)]
+    pub catalogue: Option<PathBuf>,
+
+    /// Largest request body accepted, in megabytes; bounds uploaded documents and sheets
+    #[arg(long, default_value = "64")]
+    pub max_upload: usize,
+
+    #[command(flatten)]
+    pub llm: LLMArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct ModelsCLIArgs {
+    /// Model catalogue (JSON) describing the models offered
+    #[arg(long)]
+    pub catalogue: Option<PathBuf>,
+
+    /// Print as JSON, the same as `GET /api/models`
+    #[arg(long)]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub llm: LLMArgs,
+}
+
+/// Connection to the LLM provider. Each setting left out falls back to its
+/// environment variable, then to the `--llm-config` file.
+#[derive(Args, Debug, Clone)]
+pub struct LLMArgs {
+    /// JSON file with any of `api_base`, `api_key`, `org_id`, `project_id`,
+    /// `headers`, `query` and `timeout`
+    #[arg(long)]
+    llm_config: Option<PathBuf>,
+
+    /// Provider endpoint. [env: OPENAI_API_BASE] [default: https://api.openai.com/v1]
+    #[arg(long)]
+    api_base: Option<String>,
+
+    /// API key sent as a bearer token. [env: OPENAI_API_KEY]
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// Organization sent as `OpenAI-Organization`. [env: OPENAI_ORG_ID]
+    #[arg(long)]
+    org_id: Option<String>,
+
+    /// Project sent as `OpenAI-Project`. [env: OPENAI_PROJECT_ID]
+    #[arg(long)]
+    project_id: Option<String>,
+
+    /// Extra request header as NAME=VALUE; repeatable. [env: OPENAI_HEADERS, comma separated]
+    #[arg(long = "header", value_parser = parse_pair)]
+    headers: Vec<(String, String)>,
+
+    /// Extra query parameter as NAME=VALUE; repeatable. [env: OPENAI_QUERY, comma separated]
+    #[arg(long = "query", value_parser = parse_pair)]
+    query: Vec<(String, String)>,
+
+    /// Seconds before a request to the provider is given up. [env: OPENAI_TIMEOUT]
+    #[arg(long)]
+    timeout: Option<f64>,
+}
+
+/// Settings read from `--llm-config`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LLMConfigFile {
+    api_base: Option<String>,
+    api_key: Option<String>,
+    org_id: Option<String>,
+    project_id: Option<String>,
+    headers: BTreeMap<String, String>,
+    query: BTreeMap<String, String>,
+    timeout: Option<f64>,
+}
+
+fn parse_pair(pair: &str) -> std::result::Result<(String, String), String> {
+    pair.split_once('=')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or(format!("expected NAME=VALUE, got `{pair}`"))
+}
+
+#[derive(Parser, Debug)]
+pub struct UserCLIArgs {
+    /// Data directory of the web server
+    #[arg(long, default_value = "./data")]
+    pub data: PathBuf,
+
+    #[command(subcommand)]
+    pub command: UserCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// Add a user; prompts for the password if not given
+    Add {
+        name: String,
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Remove a user
+    Remove { name: String },
+    /// List all users
+    List,
 }
 
 #[derive(Debug, Clone)]
@@ -87,27 +329,84 @@ pub struct Job {
     pub input: PathBuf,
     pub inter_sheet: PathBuf,
     pub output: PathBuf,
+    pub document: DocumentArgs,
     pub llm: LLM,
     pub system: String,
     pub user: String,
     pub parallel: usize,
+    pub retry: RetryPolicy,
+}
+
+/// When to send a chat request again; failed requests and rejected answers
+/// have separate limits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub transport_retries: u8,
+    /// Tries of the `corrective` and `model` fallbacks each.
+    pub validation_retries: u8,
+    /// Seconds before the first resend.
+    pub retry_delay: f64,
+    /// Cap in seconds of the exponential backoff.
+    pub max_retry_delay: f64,
+    pub fallbacks: Vec<Fallback>,
+    pub fallback_model: Option<String>,
+}
+
+/// A step down from asking for the whole chunk with its markers in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Fallback {
+    /// Ask again, pointing out the markers that went missing.
+    Corrective,
+    /// Ask `fallback_model` instead, with the same correction.
+    Model,
+    /// Translate the text between markers one piece at a time.
+    Segments,
+    /// Translate without markers and put the whole text in the first segment,
+    /// dropping the formatting of the chunk.
+    Collapse,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            transport_retries: 5,
+            validation_retries: 3,
+            retry_delay: 1.0,
+            max_retry_delay: 60.0,
+            fallbacks: vec![
+                Fallback::Corrective,
+                Fallback::Model,
+                Fallback::Segments,
+                Fallback::Collapse,
+            ],
+            fallback_model: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LLM {
     pub url: String,
     pub api_key: Option<String>,
+    pub org_id: Option<String>,
+    pub project_id: Option<String>,
+    /// Sent with every request, after the ones above.
+    pub headers: HeaderMap,
+    pub query: Vec<(String, String)>,
+    pub timeout: Option<Duration>,
     pub model: String,
 }
 
 fn suffix_fallback(
     src: &Option<PathBuf>,
-    template: &PathBuf,
+    template: &Path,
     suffix: String,
     overwritten_extension: Option<&OsStr>,
 ) -> PathBuf {
     src.clone().unwrap_or_else(|| {
-        let input = template.clone();
+        let input = template.to_path_buf();
         let parent = input.parent().unwrap_or(Path::new(""));
         let file_name = input.file_name().unwrap_or(OsStr::new(""));
         let stem = Path::new(file_name).file_stem().unwrap_or(OsStr::new(""));
@@ -125,52 +424,280 @@ fn suffix_fallback(
     })
 }
 
-pub fn transform_job_cli(job_cli: JobCLIArgs) -> Job {
-    dotenv::dotenv().ok();
+/// `<INPUT>-inter.csv` unless given.
+pub fn inter_sheet_fallback(src: &Option<PathBuf>, input: &Path) -> PathBuf {
+    suffix_fallback(src, input, "-inter".to_string(), Some(OsStr::new("csv")))
+}
+
+/// `<INPUT>-translated.<EXT>` unless given.
+pub fn output_fallback(src: &Option<PathBuf>, input: &Path) -> PathBuf {
+    suffix_fallback(src, input, "-translated".to_string(), None)
+}
+
+pub const DEFAULT_META_KEYS: [&str; 5] =
+    ["title", "subtitle", "abstract", "description", "keywords"];
 
-    let system_prompt = "You are an expert translator. Please translate {{ source_language }} into {{ target_language }}. The user will submit sentences or paragraphs with some contexts; please only translate the intended text into {{ target_language }}.
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are an expert translator. Please translate {{ source_language }} into {{ target_language }}. The user will submit sentences or paragraphs with some contexts; please only translate the intended text into {{ target_language }}.
 
 - If there are symbols {{ special_tokens | join(\" , \") }}, keep the symbol intact on the result text in the correct position.
-- Do not give any alternative translation or including any previous context, notes or discussion.".to_string();
-    let user_prompt = "
+- Do not give any alternative translation or including any previous context, notes or discussion.";
+
+pub const DEFAULT_USER_PROMPT: &str = "
 {%- set previous_chunks = previous_chunks[-8:] -%}
+{%- if previous_chunks or next_chunks -%}
 {%- if previous_chunks -%}
 Given the previous context:
 
 {{ previous_chunks | join(\"\\n\\n\") }}
 
+{% endif -%}
+{%- if next_chunks -%}
+Given the next context:
+
+{{ next_chunks | join(\"\\n\\n\") }}
+
+{% endif -%}
 Only translate the following text:
 
 {% endif -%}
-{{ source_text }}"
-        .to_string();
-
-    return Job {
-        inter_sheet: suffix_fallback(
-            &job_cli.inter_sheet,
-            &job_cli.input,
-            "-inter".to_string(),
-            Some(OsStr::new("csv")),
-        ),
-        output: suffix_fallback(
-            &job_cli.output,
-            &job_cli.input,
-            "-translated".to_string(),
-            None,
-        ),
-        system: job_cli.system.clone().unwrap_or(system_prompt),
-        user: job_cli.user.clone().unwrap_or(user_prompt),
+{{ source_text }}";
+
+/// An environment variable, if set.
+fn env_var(key: &str) -> Result<Option<String>> {
+    std::env::var_os(key)
+        .map(|value| {
+            value
+                .into_string()
+                .map_err(|_| TrenError::Config(format!("{key} is not valid UTF-8")))
+        })
+        .transpose()
+}
+
+/// `NAME=VALUE` pairs of a comma separated environment variable.
+fn env_pairs(
+    env: &impl Fn(&str) -> Result<Option<String>>,
+    key: &str,
+) -> Result<Vec<(String, String)>> {
+    env(key)?
+        .iter()
+        .flat_map(|list| list.split(','))
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| parse_pair(pair).map_err(|err| TrenError::Config(format!("{key}: {err}"))))
+        .collect()
+}
+
+fn parse_seconds(seconds: f64, source: &str) -> Result<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or(TrenError::Config(format!(
+            "{source}: timeout must be a positive number of seconds, got {seconds}"
+        )))
+}
+
+impl LLM {
+    /// Resolve the provider settings: flags first, then environment variables,
+    /// then the `--llm-config` file.
+    pub fn new(args: &LLMArgs, model: String) -> Result<Self> {
+        dotenv::dotenv().ok();
+        Self::resolve(args, model, env_var)
+    }
+
+    /// `new` with the environment variables looked up by `env`.
+    fn resolve(
+        args: &LLMArgs,
+        model: String,
+        env: impl Fn(&str) -> Result<Option<String>>,
+    ) -> Result<Self> {
+        let file = match &args.llm_config {
+            Some(path) => {
+                let bad = |err: &dyn std::fmt::Display| {
+                    TrenError::Config(format!("cannot read {}: {err}", path.display()))
+                };
+                let bytes = std::fs::read(path).map_err(|err| bad(&err))?;
+                serde_json::from_slice::<LLMConfigFile>(&bytes).map_err(|err| bad(&err))?
+            }
+            None => LLMConfigFile::default(),
+        };
+        let setting = |flag: &Option<String>, key: &str, from_file: Option<String>| {
+            Ok::<_, TrenError>(match flag {
+                Some(value) => Some(value.clone()),
+                None => env(key)?.or(from_file),
+            })
+        };
+
+        // later pairs replace earlier ones with the same name
+        let mut headers = file.headers.into_iter().collect::<BTreeMap<_, _>>();
+        headers.extend(env_pairs(&env, "OPENAI_HEADERS")?);
+        headers.extend(args.headers.iter().cloned());
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| {
+                let header = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|err| TrenError::Config(format!("bad header name {name}: {err}")))?;
+                let value = HeaderValue::from_str(&value).map_err(|err| {
+                    TrenError::Config(format!("bad value of header {name}: {err}"))
+                })?;
+                Ok((header, value))
+            })
+            .collect::<Result<HeaderMap>>()?;
+        let mut query = file.query;
+        query.extend(env_pairs(&env, "OPENAI_QUERY")?);
+        query.extend(args.query.iter().cloned());
+
+        let timeout = match args.timeout {
+            Some(seconds) => Some(parse_seconds(seconds, "--timeout")?),
+            None => match env("OPENAI_TIMEOUT")? {
+                Some(seconds) => Some(parse_seconds(
+                    seconds.trim().parse().map_err(|_| {
+                        TrenError::Config(format!("OPENAI_TIMEOUT is not a number: {seconds}"))
+                    })?,
+                    "OPENAI_TIMEOUT",
+                )?),
+                None => file
+                    .timeout
+                    .map(|seconds| parse_seconds(seconds, "timeout in --llm-config"))
+                    .transpose()?,
+            },
+        };
+
+        // org and project go out as headers too
+        let org_id = setting(&args.org_id, "OPENAI_ORG_ID", file.org_id)?;
+        let project_id = setting(&args.project_id, "OPENAI_PROJECT_ID", file.project_id)?;
+        for (key, value) in [("org id", &org_id), ("project id", &project_id)] {
+            if let Some(value) = value {
+                HeaderValue::from_str(value)
+                    .map_err(|err| TrenError::Config(format!("bad {key} {value}: {err}")))?;
+            }
+        }
+
+        Ok(LLM {
+            url: setting(&args.api_base, "OPENAI_API_BASE", file.api_base)?
+                .unwrap_or("https://api.openai.com/v1".to_string()),
+            api_key: setting(&args.api_key, "OPENAI_API_KEY", file.api_key)?,
+            org_id,
+            project_id,
+            headers,
+            query: query.into_iter().collect(),
+            timeout,
+            model,
+        })
+    }
+}
+
+pub fn transform_job_cli(job_cli: JobCLIArgs) -> Result<Job> {
+    Ok(Job {
+        inter_sheet: inter_sheet_fallback(&job_cli.inter_sheet, &job_cli.input),
+        output: output_fallback(&job_cli.output, &job_cli.input),
+        document: job_cli.document,
+        system: job_cli
+            .system
+            .clone()
+            .unwrap_or(DEFAULT_SYSTEM_PROMPT.to_string()),
+        user: job_cli
+            .user
+            .clone()
+            .unwrap_or(DEFAULT_USER_PROMPT.to_string()),
         src: job_cli.src,
         tar: job_cli.tar,
         input: job_cli.input,
-        llm: LLM {
-            url: std::env::var_os("OPENAI_API_BASE")
-                .unwrap_or("https://api.openai.com/v1".into())
-                .into_string()
-                .unwrap(),
-            api_key: std::env::var_os("OPENAI_API_KEY").map(|s| s.into_string().unwrap()),
-            model: job_cli.model,
-        },
+        llm: LLM::new(&job_cli.llm, job_cli.model)?,
         parallel: job_cli.parallel,
-    };
+        retry: RetryPolicy {
+            transport_retries: job_cli.transport_retries,
+            validation_retries: job_cli.validation_retries,
+            retry_delay: job_cli.retry_delay,
+            max_retry_delay: job_cli.max_retry_delay,
+            fallbacks: job_cli.fallbacks,
+            fallback_model: job_cli.fallback_model,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Provider settings from `flags`, the environment `env` and a config
+    /// file holding `file`.
+    fn llm(flags: &[&str], env: &[(&str, &str)], file: serde_json::Value) -> LLM {
+        let path = std::env::temp_dir().join(format!("tren-{}.json", uuid::Uuid::now_v7()));
+        std::fs::write(&path, file.to_string()).unwrap();
+        let config = path.to_string_lossy().to_string();
+        let args = ["tren", "--llm-config", &config]
+            .into_iter()
+            .chain(flags.iter().copied());
+        let args = ModelsCLIArgs::try_parse_from(args).unwrap().llm;
+        let env = env
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        let llm = LLM::resolve(&args, "model".to_string(), |key| Ok(env.get(key).cloned()));
+        std::fs::remove_file(path).unwrap();
+        llm.unwrap()
+    }
+
+    fn file() -> serde_json::Value {
+        serde_json::json!({
+            "api_base": "http://file",
+            "api_key": "file-key",
+            "headers": { "X-Team": "file", "X-Source": "file" },
+            "timeout": 30,
+        })
+    }
+
+    fn env() -> [(&'static str, &'static str); 4] {
+        [
+            ("OPENAI_API_BASE", "http://env"),
+            ("OPENAI_API_KEY", "env-key"),
+            ("OPENAI_HEADERS", "X-Source=env"),
+            ("OPENAI_TIMEOUT", "20"),
+        ]
+    }
+
+    #[test]
+    fn flags_come_first() {
+        let flags = [
+            "--api-base",
+            "http://flag",
+            "--api-key",
+            "flag-key",
+            "--header",
+            "X-Source=flag",
+            "--timeout",
+            "10",
+        ];
+        let llm = llm(&flags, &env(), file());
+        assert_eq!(llm.url, "http://flag");
+        assert_eq!(llm.api_key.as_deref(), Some("flag-key"));
+        assert_eq!(llm.headers["x-source"], "flag");
+        // headers are merged by name
+        assert_eq!(llm.headers["x-team"], "file");
+        assert_eq!(llm.timeout, Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn environment_comes_before_the_file() {
+        let llm = llm(&[], &env(), file());
+        assert_eq!(llm.url, "http://env");
+        assert_eq!(llm.api_key.as_deref(), Some("env-key"));
+        assert_eq!(llm.headers["x-source"], "env");
+        assert_eq!(llm.headers["x-team"], "file");
+        assert_eq!(llm.timeout, Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn file_comes_last() {
+        let from_file = llm(&[], &[], file());
+        assert_eq!(from_file.url, "http://file");
+        assert_eq!(from_file.api_key.as_deref(), Some("file-key"));
+        assert_eq!(from_file.headers["x-source"], "file");
+        assert_eq!(from_file.timeout, Some(Duration::from_secs(30)));
+
+        let default = llm(&[], &[], serde_json::json!({}));
+        assert_eq!(default.url, "https://api.openai.com/v1");
+        assert_eq!(default.api_key, None);
+        assert_eq!(default.timeout, None);
+    }
 }
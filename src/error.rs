@@ -0,0 +1,97 @@
+use crate::chunk::{TOK_SEP, TaskType};
+use std::fmt;
+
+pub type Result<T, E = TrenError> = std::result::Result<T, E>;
+
+/// Why a translation job failed; each message says what to fix.
+#[derive(Debug)]
+pub enum TrenError {
+    /// The document has a node the backend cannot read.
+    Unsupported { node: String, location: String },
+    /// A translated chunk has a different number of `TOK_SEP` segments than
+    /// its place in the document.
+    SegmentMismatch {
+        index: usize,
+        task_type: TaskType,
+        expected: usize,
+        found: usize,
+    },
+    /// There are fewer translated chunks than chunks in the document.
+    MissingChunk { index: usize, task_type: TaskType },
+    /// The LLM provider failed or answered with nothing usable.
+    Provider(String),
+    /// Pandoc failed to read or write the document.
+    Pandoc(String),
+    /// Bad environment variable, argument or prompt template.
+    Config(String),
+    /// Reading or writing a file other than the document.
+    Io(std::io::Error),
+    /// The job was cancelled or paused before it finished.
+    Cancelled,
+}
+
+impl fmt::Display for TrenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrenError::Unsupported { node, location } => {
+                write!(f, "unsupported document node at {location}: {node}")
+            }
+            TrenError::SegmentMismatch {
+                index,
+                task_type,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{task_type:?} chunk {index} has {found} {TOK_SEP}-separated segments but the document needs {expected}"
+            ),
+            TrenError::MissingChunk { index, task_type } => {
+                write!(f, "no translation for {task_type:?} chunk {index}")
+            }
+            TrenError::Provider(message) => write!(f, "LLM provider error: {message}"),
+            TrenError::Pandoc(message) => write!(f, "pandoc error: {message}"),
+            TrenError::Config(message) => write!(f, "configuration error: {message}"),
+            TrenError::Io(err) => write!(f, "{err}"),
+            TrenError::Cancelled => write!(f, "job is cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for TrenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrenError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for TrenError {
+    fn from(err: std::io::Error) -> Self {
+        TrenError::Io(err)
+    }
+}
+
+/// A sheet that cannot be read or written is an I/O error; one that reads but
+/// holds a malformed row is for the user to fix.
+impl From<csv::Error> for TrenError {
+    fn from(err: csv::Error) -> Self {
+        let message = err.to_string();
+        match err.into_kind() {
+            csv::ErrorKind::Io(err) => TrenError::Io(err),
+            _ => TrenError::Config(message),
+        }
+    }
+}
+
+impl From<pandoc::PandocError> for TrenError {
+    fn from(err: pandoc::PandocError) -> Self {
+        TrenError::Pandoc(err.to_string())
+    }
+}
+
+impl From<async_openai::error::OpenAIError> for TrenError {
+    fn from(err: async_openai::error::OpenAIError) -> Self {
+        TrenError::Provider(err.to_string())
+    }
+}
@@ -1,10 +1,18 @@
+#![allow(clippy::upper_case_acronyms)]
+
 mod chunk;
 mod cli;
+mod error;
+mod models;
+mod sheet;
 mod translate;
-use crate::cli::{CLI, CLIMode, transform_job_cli};
-use crate::translate::process_job;
+mod web;
+use crate::cli::{CLI, CLIMode, LLM, inter_sheet_fallback, output_fallback, transform_job_cli};
+use crate::sheet::{apply_sheet, read_sheet};
+use crate::translate::{print_events, process_job};
 use anyhow::Result;
 use clap::Parser;
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -13,11 +21,57 @@ async fn main() -> Result<()> {
 
     match cli_val.mode {
         CLIMode::Run(job_cli) => {
-            let job = transform_job_cli(job_cli);
-            process_job(&job).await?;
+            let resume = job_cli.resume;
+            let job = transform_job_cli(job_cli)?;
+            let previous = if resume && job.inter_sheet.exists() {
+                read_sheet(&job.inter_sheet)?
+            } else {
+                if resume {
+                    eprintln!(
+                        "{} does not exist; translating from scratch",
+                        job.inter_sheet.display()
+                    );
+                }
+                vec![]
+            };
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let printer = tokio::spawn(print_events(rx));
+            let cancel = CancellationToken::new();
+            let ctrl_c = cancel.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    eprintln!("Cancelling...");
+                    ctrl_c.cancel();
+                }
+            });
+            let result = process_job(&job, &tx, &cancel, &previous).await;
+            drop(tx);
+            printer.await?;
+            result?;
+        }
+        CLIMode::Apply(apply_cli) => {
+            let sheet = inter_sheet_fallback(&apply_cli.inter_sheet, &apply_cli.input);
+            let output = output_fallback(&apply_cli.output, &apply_cli.input);
+            apply_sheet(&apply_cli.input, &sheet, &output, &apply_cli.document)?;
         }
         CLIMode::Web(web_cli) => {
-            todo!()
+            web::serve(web_cli).await?;
+        }
+        CLIMode::User(user_cli) => {
+            web::auth::manage_users(user_cli)?;
+        }
+        CLIMode::Models(models_cli) => {
+            let catalogue = match models_cli.catalogue {
+                Some(path) => models::load_catalogue(&path)?,
+                None => vec![],
+            };
+            let models =
+                models::list_models(&LLM::new(&models_cli.llm, String::new())?, &catalogue).await?;
+            if models_cli.json {
+                println!("{}", serde_json::to_string_pretty(&models)?);
+            } else {
+                models::print_models(&models);
+            }
         }
     }
 
@@ -0,0 +1,193 @@
+use crate::cli::LLM;
+use crate::translate::llm_client;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A model offered for translation; mirrors `modelSchema` in `webui/src/lib/job.ts`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Model {
+    pub id: String,
+    /// Display name
+    #[serde(default)]
+    pub name: String,
+    /// Parameter count, e.g. `20b` or `30b a3b`
+    #[serde(default)]
+    pub params: String,
+    /// Whether the weights are open; unknown for models missing from the catalogue.
+    #[serde(default)]
+    pub open: Option<bool>,
+    #[serde(default)]
+    pub context_window: Option<u32>,
+}
+
+/// Read an admin-curated catalogue: a JSON list of models.
+pub fn load_catalogue(path: &Path) -> Result<Vec<Model>> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// Models served by the provider, described by the catalogue where possible.
+/// Catalogue entries the provider does not serve are left out; if the
+/// provider cannot list its models, the catalogue is returned as is.
+pub async fn list_models(llm: &LLM, catalogue: &[Model]) -> Result<Vec<Model>> {
+    let served = match llm_client(llm)?.models().list().await {
+        Ok(list) => list.data,
+        Err(err) if !catalogue.is_empty() => {
+            eprintln!("cannot list models of {}: {err}", llm.url);
+            return Ok(catalogue.to_vec());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut models = catalogue
+        .iter()
+        .filter(|model| served.iter().any(|s| s.id == model.id))
+        .cloned()
+        .collect::<Vec<_>>();
+    models.extend(
+        served
+            .into_iter()
+            .filter(|s| !catalogue.iter().any(|model| model.id == s.id))
+            .map(|s| Model {
+                name: s.id.clone(),
+                id: s.id,
+                params: "".to_string(),
+                open: None,
+                context_window: None,
+            }),
+    );
+    Ok(models)
+}
+
+/// Print the model list for `tren models`.
+pub fn print_models(models: &[Model]) {
+    let rows = models
+        .iter()
+        .map(|model| {
+            [
+                model.id.clone(),
+                model.name.clone(),
+                model.params.clone(),
+                match model.open {
+                    Some(true) => "open",
+                    Some(false) => "proprietary",
+                    None => "",
+                }
+                .to_string(),
+                model
+                    .context_window
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let header = ["ID", "NAME", "PARAMS", "TYPE", "CONTEXT"].map(String::from);
+    let widths = (0..header.len())
+        .map(|i| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|row| row[i].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    std::iter::once(&header).chain(&rows).for_each(|row| {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, http::HeaderMap, http::Uri, routing::get};
+    use std::sync::{Arc, Mutex};
+
+    fn model(id: &str, name: &str) -> Model {
+        Model {
+            id: id.to_string(),
+            name: name.to_string(),
+            params: "20b".to_string(),
+            open: Some(true),
+            context_window: Some(131072),
+        }
+    }
+
+    fn llm(url: String) -> LLM {
+        LLM {
+            url,
+            api_key: Some("key".to_string()),
+            org_id: None,
+            project_id: None,
+            headers: [("x-team".parse().unwrap(), "tren".parse().unwrap())]
+                .into_iter()
+                .collect(),
+            query: vec![("api-version".to_string(), "1".to_string())],
+            timeout: None,
+            model: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn served_models_are_described_by_the_catalogue() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        let router = Router::new().route(
+            "/v1/models",
+            get(move |uri: Uri, headers: HeaderMap| async move {
+                log.lock().unwrap().push((uri, headers));
+                Json(serde_json::json!({
+                    "object": "list",
+                    "data": [
+                        { "id": "stub/served", "object": "model", "created": 0, "owned_by": "stub" },
+                        { "id": "stub/unknown", "object": "model", "created": 0, "owned_by": "stub" },
+                    ],
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let catalogue = [model("stub/served", "Served"), model("stub/gone", "Gone")];
+        let models = list_models(&llm(url), &catalogue).await.unwrap();
+        let described = models
+            .iter()
+            .map(|model| (model.id.as_str(), model.name.as_str(), model.open))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            described,
+            [
+                ("stub/served", "Served", Some(true)),
+                ("stub/unknown", "stub/unknown", None)
+            ]
+        );
+
+        let requests = requests.lock().unwrap();
+        let (uri, headers) = &requests[0];
+        assert_eq!(requests.len(), 1);
+        assert_eq!(uri.query(), Some("api-version=1"));
+        assert_eq!(headers["authorization"], "Bearer key");
+        assert_eq!(headers["x-team"], "tren");
+    }
+
+    #[tokio::test]
+    async fn the_catalogue_stands_in_for_an_unreachable_provider() {
+        // nothing listens on the discard port
+        let catalogue = [model("stub/served", "Served")];
+        let models = list_models(&llm("http://127.0.0.1:9".to_string()), &catalogue)
+            .await
+            .unwrap();
+        assert_eq!(models.len(), 1);
+        assert!(
+            list_models(&llm("http://127.0.0.1:9".to_string()), &[])
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use crate::chunk::{TOK_SEP, TaskType, Tasks, open_document};
+use crate::cli::DocumentArgs;
+use crate::error::{Result, TrenError};
+use crate::translate::Record;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+/// Write translated chunks to the intermediate sheet, one row per chunk:
+/// `index, task_type, source, target, attempts, fallback, prompt_tokens,
+/// completion_tokens`, where `fallback` names how a chunk that kept losing its
+/// markers was translated in the end.
+pub fn write_sheet<'a>(
+    path: &Path,
+    records: impl IntoIterator<Item = &'a Record>,
+) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read back an intermediate sheet, possibly edited by a reviewer.
+pub fn read_sheet(path: &Path) -> Result<Vec<Record>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    Ok(reader.deserialize().collect::<Result<Vec<Record>, _>>()?)
+}
+
+/// Line the sheet up with the chunks of the document: every chunk needs
+/// exactly one row with the same source text, and the translation must keep
+/// every formatting marker. A chunk without a row is a `MissingChunk`; every
+/// other mismatch is listed with its line.
+fn tasks_from_sheet(mipcs: &Tasks, records: &[Record]) -> Result<Tasks> {
+    // the first row of each chunk, with its position in the sheet
+    let mut rows: HashMap<(TaskType, usize), (usize, &Record)> = HashMap::new();
+    for (row, record) in records.iter().enumerate() {
+        rows.entry((record.task_type.clone(), record.index))
+            .or_insert((row, record));
+    }
+    let mut missing: Option<TrenError> = None;
+    let mut errors: Vec<String> = vec![];
+    let mut lined_up = |sources: &VecDeque<String>, task_type: TaskType| {
+        let count = records
+            .iter()
+            .filter(|record| record.task_type == task_type)
+            .count();
+        if count != sources.len() {
+            errors.push(format!(
+                "{} {task_type:?} chunks in the document but {count} rows in the sheet",
+                sources.len(),
+            ));
+        }
+        // line numbers in the file; line 1 is the header
+        sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                let Some((row, record)) = rows.get(&(task_type.clone(), i)) else {
+                    missing.get_or_insert(TrenError::MissingChunk {
+                        index: i,
+                        task_type: task_type.clone(),
+                    });
+                    return source.clone();
+                };
+                if record.source != *source {
+                    errors.push(format!(
+                        "line {}: source text differs from {task_type:?} chunk {i}",
+                        row + 2
+                    ));
+                }
+                let src_tok_count = source.chars().filter(|c| *c == TOK_SEP).count();
+                let tar_tok_count = record.target.chars().filter(|c| *c == TOK_SEP).count();
+                if src_tok_count != tar_tok_count {
+                    errors.push(format!(
+                        "line {}: {tar_tok_count} {TOK_SEP} markers in the target, expected {src_tok_count}",
+                        row + 2
+                    ));
+                }
+                record.target.clone()
+            })
+            .collect::<VecDeque<_>>()
+    };
+
+    let tasks = Tasks::with_chunks(
+        lined_up(&mipcs.main, TaskType::Main),
+        lined_up(&mipcs.sides, TaskType::Side),
+    );
+    if let Some(err) = missing {
+        return Err(err);
+    }
+    if !errors.is_empty() {
+        return Err(TrenError::Config(format!(
+            "the sheet does not match the document:\n{}",
+            errors.join("\n")
+        )));
+    }
+    Ok(tasks)
+}
+
+/// Rebuild the output document from the input and an edited intermediate
+/// sheet, without calling the LLM.
+pub fn apply_sheet(
+    input: &Path,
+    sheet: &Path,
+    output: &Path,
+    document: &DocumentArgs,
+) -> Result<()> {
+    let mut ast = open_document(input, output, document)?;
+
+    let records = read_sheet(sheet)?;
+    let tasks = tasks_from_sheet(&ast.to_mipcs(), &records)?;
+
+    ast.apply_mipcs(tasks)?;
+    ast.export(output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(index: usize, task_type: TaskType, source: &str, target: &str) -> Record {
+        Record {
+            index,
+            task_type,
+            source: source.to_string(),
+            target: target.to_string(),
+            attempts: 1,
+            fallback: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+        }
+    }
+
+    fn document() -> Tasks {
+        Tasks::with_chunks(
+            ["Title".into(), "Hello 𐑙world".into()].into(),
+            ["A note".into()].into(),
+        )
+    }
+
+    #[test]
+    fn rows_line_up_by_type_and_index() {
+        // rows of a sheet sorted by hand still line up
+        let records = [
+            record(0, TaskType::Side, "A note", "Une note"),
+            record(1, TaskType::Main, "Hello 𐑙world", "Bonjour 𐑙monde"),
+            record(0, TaskType::Main, "Title", "Titre"),
+        ];
+        let tasks = tasks_from_sheet(&document(), &records).unwrap();
+        assert_eq!(tasks.main, ["Titre", "Bonjour 𐑙monde"]);
+        assert_eq!(tasks.sides, ["Une note"]);
+    }
+
+    #[test]
+    fn every_mismatch_is_reported_with_its_line() {
+        let records = [
+            record(0, TaskType::Main, "Title", "Titre"),
+            record(1, TaskType::Main, "Hello 𐑙world!", "Bonjour monde"),
+            record(0, TaskType::Side, "A note", "Une note"),
+            record(1, TaskType::Side, "Another note", "Une autre note"),
+        ];
+        let Err(TrenError::Config(err)) = tasks_from_sheet(&document(), &records) else {
+            panic!("the sheet should not apply");
+        };
+        assert_eq!(
+            err,
+            "the sheet does not match the document:
+line 3: source text differs from Main chunk 1
+line 3: 0 𐑙 markers in the target, expected 1
+1 Side chunks in the document but 2 rows in the sheet"
+        );
+    }
+
+    #[test]
+    fn a_chunk_without_a_row_is_missing() {
+        let records = [
+            record(0, TaskType::Main, "Title", "Titre"),
+            record(1, TaskType::Main, "Hello 𐑙world", "Bonjour 𐑙monde"),
+        ];
+        assert!(matches!(
+            tasks_from_sheet(&document(), &records),
+            Err(TrenError::MissingChunk {
+                index: 0,
+                task_type: TaskType::Side
+            })
+        ));
+    }
+
+    #[test]
+    fn a_malformed_row_is_for_the_user_to_fix() {
+        let path = std::env::temp_dir().join(format!("tren-{}.csv", uuid::Uuid::now_v7()));
+        std::fs::write(
+            &path,
+            "index,task_type,source,target,attempts,fallback,prompt_tokens,completion_tokens\n\
+             first,Main,Title,Titre,1,,,\n",
+        )
+        .unwrap();
+        let read = read_sheet(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(read, Err(TrenError::Config(_))));
+        assert!(matches!(
+            read_sheet(&path),
+            Err(TrenError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn sheet_round_trips_with_fallbacks() {
+        let path = std::env::temp_dir().join(format!("tren-{}.csv", uuid::Uuid::now_v7()));
+        let mut edited = record(1, TaskType::Main, "Hello 𐑙world", "Bonjour, monde𐑙");
+        edited.fallback = Some(crate::cli::Fallback::Collapse);
+        let records = [record(0, TaskType::Main, "Title", "Titre"), edited];
+        write_sheet(&path, &records).unwrap();
+        let read = read_sheet(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].target, "Bonjour, monde𐑙");
+        assert_eq!(read[1].fallback, Some(crate::cli::Fallback::Collapse));
+        assert_eq!(read[0].fallback, None);
+    }
+}
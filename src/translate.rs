@@ -1,142 +1,1113 @@
 use crate::chunk::{TOK_SEP, TaskType, Tasks};
-use crate::cli::Job;
-use anyhow::{Result, anyhow};
+use crate::cli::{Fallback, Job, LLM, RetryPolicy};
+use crate::error::{Result, TrenError};
+use crate::sheet::write_sheet;
 use async_openai::{
     Client,
-    config::OpenAIConfig,
+    config::{Config, OpenAIConfig},
     types::chat::{
+        ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
         ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
-        CreateChatCompletionRequestArgs,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
     },
 };
 use futures::{StreamExt, stream};
-use minijinja::render;
+use minijinja::{Environment, Value, context};
+use reqwest::header::{AUTHORIZATION, HeaderMap};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
 
-async fn chat(client: &Client<OpenAIConfig>, job: Job, payload: String) -> Result<String> {
-    // simple case
-    if payload.trim().len() == 0 {
-        return Ok(payload.clone());
+/// One translated chunk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub index: usize,
+    pub task_type: TaskType,
+    pub source: String,
+    pub target: String,
+    pub attempts: u8,
+    /// How the translation was made when the plain request kept failing;
+    /// worth a human look.
+    #[serde(default)]
+    pub fallback: Option<Fallback>,
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+}
+
+/// Progress of a running job, in the order it happens.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Document is chunked; `total` chunks will be translated.
+    Started { total: usize },
+    /// A request for a chunk failed or its answer was rejected; it is sent
+    /// again as attempt `attempts`.
+    Retry {
+        index: usize,
+        task_type: TaskType,
+        attempts: u8,
+        reason: String,
+    },
+    /// A chunk is translated; `done` out of `total` chunks are finished.
+    Chunk {
+        #[serde(flatten)]
+        record: Record,
+        done: usize,
+        total: usize,
+    },
+    /// Output document is written.
+    Finished,
+}
+
+/// Print events on the terminal; used by `tren run`.
+pub async fn print_events(mut events: UnboundedReceiver<Event>) {
+    while let Some(event) = events.recv().await {
+        if let Event::Chunk {
+            record:
+                Record {
+                    source,
+                    target,
+                    attempts,
+                    fallback,
+                    prompt_tokens,
+                    completion_tokens,
+                    ..
+                },
+            ..
+        } = event
+        {
+            println!(
+                "--- Source {}---
+{}
+--- Target {}---
+{}
+",
+                if let Some(tokens) = prompt_tokens {
+                    format!("{tokens} tokens")
+                } else {
+                    "".to_string()
+                },
+                source,
+                {
+                    let mut res: Vec<String> = vec![];
+                    if let Some(tokens) = completion_tokens {
+                        res.push(format!("{tokens} tokens"));
+                    }
+                    if attempts > 1 {
+                        res.push(format!("{attempts} attempts"));
+                    }
+                    if let Some(fallback) = fallback {
+                        res.push(format!("{fallback:?} fallback"));
+                    }
+                    res
+                }
+                .join(", "),
+                target
+            );
+        }
+    }
+}
+
+struct Answer {
+    text: String,
+    attempts: u8,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    fallback: Option<Fallback>,
+}
+
+/// Why a chat request failed.
+enum Failure {
+    /// Worth sending again: the connection failed, or the server answered
+    /// 408, 429 or 5xx, possibly with a `Retry-After` hint.
+    Transient {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    Fatal(TrenError),
+}
+
+/// Wait before resending, from the `Retry-After` or `retry-after-ms` header:
+/// either seconds or an HTTP date.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok().map(str::trim);
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        return Duration::try_from_secs_f64(ms / 1000.0).ok();
+    }
+    let value = header("retry-after")?;
+    match value.parse::<f64>() {
+        Ok(secs) => Duration::try_from_secs_f64(secs).ok(),
+        Err(_) => {
+            let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (at.to_utc() - chrono::Utc::now()).to_std().ok()
+        }
+    }
+}
+
+/// Exponential backoff with jitter: somewhere between half and all of
+/// `retry_delay * 2^(failures - 1)`, capped at `max_retry_delay`.
+fn backoff(policy: &RetryPolicy, failures: u8) -> Duration {
+    let delay =
+        (policy.retry_delay * 2f64.powi(i32::from(failures) - 1)).min(policy.max_retry_delay);
+    Duration::try_from_secs_f64(delay * (0.5 + rand::random::<f64>() / 2.0)).unwrap_or_default()
+}
+
+/// Send a chat completion request. It goes through `reqwest` rather than
+/// `Client::chat` so the status code and headers decide whether to retry.
+async fn send_chat(
+    http: &reqwest::Client,
+    config: &ProviderConfig,
+    request: &CreateChatCompletionRequest,
+) -> Result<CreateChatCompletionResponse, Failure> {
+    let transient = |message: String| Failure::Transient {
+        message,
+        retry_after: None,
+    };
+    let response = http
+        .post(config.url("/chat/completions"))
+        .query(&config.query())
+        .headers(config.headers())
+        .json(request)
+        .send()
+        .await
+        .map_err(|err| transient(err.to_string()))?;
+
+    let status = response.status();
+    let hint = retry_after(response.headers());
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| transient(err.to_string()))?;
+    if status.is_success() {
+        return serde_json::from_slice(&body).map_err(|err| {
+            Failure::Fatal(TrenError::Provider(format!("unreadable answer: {err}")))
+        });
+    }
+
+    let message = format!("{status}: {}", String::from_utf8_lossy(&body).trim());
+    let out_of_quota = message.contains("insufficient_quota");
+    if status.is_server_error()
+        || status.as_u16() == 408
+        || (status.as_u16() == 429 && !out_of_quota)
+    {
+        Err(Failure::Transient {
+            message,
+            retry_after: hint,
+        })
+    } else {
+        Err(Failure::Fatal(TrenError::Provider(message)))
+    }
+}
+
+/// Requests made for one chunk: counts the attempts, adds up the tokens and
+/// resends on transport failures as the policy allows.
+struct Asking<'a> {
+    client: (&'a reqwest::Client, &'a ProviderConfig),
+    policy: &'a RetryPolicy,
+    chunk: (usize, TaskType),
+    events: &'a UnboundedSender<Event>,
+    attempts: u8,
+    transport_failures: u8,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+}
+
+impl Asking<'_> {
+    /// Announce that another request is about to be sent.
+    fn retry(&self, reason: String) {
+        let (index, task_type) = self.chunk.clone();
+        self.events
+            .send(Event::Retry {
+                index,
+                task_type,
+                attempts: self.attempts.saturating_add(1),
+                reason,
+            })
+            .ok();
     }
 
-    let mut attempts = 1u8;
-    loop {
+    async fn ask(
+        &mut self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<String> {
+        let (index, task_type) = self.chunk.clone();
         let request = CreateChatCompletionRequestArgs::default()
-            .model(job.llm.model.clone())
-            .messages(vec![
-                ChatCompletionRequestSystemMessage::from(job.system.clone()).into(),
-                ChatCompletionRequestUserMessage::from(job.user.clone()).into(),
-            ])
+            .model(model)
+            .messages(messages)
             .n(1)
             .build()?;
 
-        let response = client.chat().create(request).await?;
-
-        let answer = response
-            .choices
-            .first()
-            .ok_or(anyhow!("no choices?"))?
-            .message
-            .content
-            .clone()
-            .ok_or(anyhow!("content error"))?;
-
-        // special token check
-        let src_tok_count = payload.chars().filter(|c| *c == TOK_SEP).count();
-        let tar_tok_count = answer.chars().filter(|c| *c == TOK_SEP).count();
-        if src_tok_count != tar_tok_count {
-            attempts += 1;
-            continue;
+        loop {
+            self.attempts = self.attempts.saturating_add(1);
+            let response = match send_chat(self.client.0, self.client.1, &request).await {
+                Ok(response) => response,
+                Err(Failure::Transient {
+                    message,
+                    retry_after,
+                }) => {
+                    self.transport_failures = self.transport_failures.saturating_add(1);
+                    if self.transport_failures > self.policy.transport_retries {
+                        return Err(TrenError::Provider(format!(
+                            "{message} ({task_type:?} chunk {index}, giving up at attempt {})",
+                            self.attempts
+                        )));
+                    }
+                    self.retry(message);
+                    tokio::time::sleep(
+                        retry_after.unwrap_or(backoff(self.policy, self.transport_failures)),
+                    )
+                    .await;
+                    continue;
+                }
+                Err(Failure::Fatal(err)) => return Err(err),
+            };
+
+            if let Some(usage) = &response.usage {
+                self.prompt_tokens = Some(self.prompt_tokens.unwrap_or(0) + usage.prompt_tokens);
+                self.completion_tokens =
+                    Some(self.completion_tokens.unwrap_or(0) + usage.completion_tokens);
+            }
+            return response
+                .choices
+                .into_iter()
+                .next()
+                .ok_or(TrenError::Provider(format!(
+                    "no choices in the answer for {task_type:?} chunk {index}"
+                )))?
+                .message
+                .content
+                .ok_or(TrenError::Provider(format!(
+                    "no content in the answer for {task_type:?} chunk {index}"
+                )));
+        }
+    }
+
+    fn answer(self, text: String, fallback: Option<Fallback>) -> Answer {
+        Answer {
+            text,
+            attempts: self.attempts,
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens,
+            fallback,
         }
+    }
+}
 
-        let usage = response.usage;
+fn count_markers(text: &str) -> usize {
+    text.chars().filter(|c| *c == TOK_SEP).count()
+}
 
-        println!(
-            "--- Source {}---
-{}
---- Target {}---
-{}
-",
-            if let Some(ref tokens) = usage {
-                format!("{} tokens", tokens.prompt_tokens)
-            } else {
-                "".to_string()
-            },
-            payload,
-            {
-                let mut res: Vec<String> = vec![];
-                if let Some(ref tokens) = usage {
-                    res.push(format!("{} tokens", tokens.completion_tokens));
+/// Translate a chunk. When the answers keep losing `TOK_SEP` markers, step
+/// down through the policy's fallbacks instead of asking the same again.
+async fn chat(
+    client: (&reqwest::Client, &ProviderConfig),
+    job: Job,
+    payload: String,
+    chunk: (usize, TaskType),
+    events: &UnboundedSender<Event>,
+) -> Result<Answer> {
+    // simple case
+    if payload.trim().is_empty() {
+        return Ok(Answer {
+            text: payload.clone(),
+            attempts: 0,
+            prompt_tokens: None,
+            completion_tokens: None,
+            fallback: None,
+        });
+    }
+
+    let policy = &job.retry;
+    let mut asking = Asking {
+        client,
+        policy,
+        chunk: chunk.clone(),
+        events,
+        attempts: 0,
+        transport_failures: 0,
+        prompt_tokens: None,
+        completion_tokens: None,
+    };
+    let system: ChatCompletionRequestMessage =
+        ChatCompletionRequestSystemMessage::from(job.system.clone()).into();
+    let user = |text: String| -> ChatCompletionRequestMessage {
+        ChatCompletionRequestUserMessage::from(text).into()
+    };
+
+    let expected = count_markers(&payload);
+    let mut answer = asking
+        .ask(&job.llm.model, vec![system.clone(), user(job.user.clone())])
+        .await?;
+    if count_markers(&answer) == expected {
+        return Ok(asking.answer(answer, None));
+    }
+
+    for fallback in &policy.fallbacks {
+        let rejected = format!(
+            "{} {TOK_SEP} markers in the answer, expected {expected}",
+            count_markers(&answer)
+        );
+        match fallback {
+            Fallback::Corrective | Fallback::Model => {
+                let model = match (fallback, &policy.fallback_model) {
+                    (Fallback::Model, Some(model)) => model,
+                    (Fallback::Model, None) => continue,
+                    _ => &job.llm.model,
+                };
+                for _ in 0..policy.validation_retries {
+                    asking.retry(format!(
+                        "{} {TOK_SEP} markers in the answer, expected {expected}; asking {model} again with a correction",
+                        count_markers(&answer)
+                    ));
+                    let correction = format!(
+                        "Your translation has {} {TOK_SEP} symbols but the text has {expected}. \
+                         Translate the text again with exactly {expected} {TOK_SEP} symbols, \
+                         each where it belongs in the translated sentence.",
+                        count_markers(&answer)
+                    );
+                    let messages = vec![
+                        system.clone(),
+                        user(job.user.clone()),
+                        ChatCompletionRequestAssistantMessage::from(answer.clone()).into(),
+                        user(correction),
+                    ];
+                    answer = asking.ask(model, messages).await?;
+                    if count_markers(&answer) == expected {
+                        return Ok(asking.answer(answer, Some(*fallback)));
+                    }
+                }
+            }
+            Fallback::Segments => {
+                asking.retry(format!("{rejected}; translating the segments one by one"));
+                let mut pieces = vec![];
+                for segment in payload.split(TOK_SEP) {
+                    if segment.trim().is_empty() {
+                        pieces.push(segment.to_string());
+                        continue;
+                    }
+                    let request = format!(
+                        "Here is a text:\n\n{}\n\nTranslate only this part of it and answer \
+                         with its translation alone:\n\n{}",
+                        payload.replace(TOK_SEP, ""),
+                        segment.trim()
+                    );
+                    let piece = asking
+                        .ask(&job.llm.model, vec![system.clone(), user(request)])
+                        .await?
+                        .replace(TOK_SEP, "");
+                    if piece.trim().is_empty() {
+                        break;
+                    }
+                    // keep the spacing around the segment, which joins it to its neighbours
+                    let lead = &segment[..segment.len() - segment.trim_start().len()];
+                    let trail = &segment[segment.trim_end().len()..];
+                    pieces.push(format!("{lead}{}{trail}", piece.trim()));
                 }
-                if attempts > 1 {
-                    res.push(format!("{attempts} attempts"));
+                if pieces.len() == expected + 1 {
+                    return Ok(asking.answer(pieces.join(&TOK_SEP.to_string()), Some(*fallback)));
                 }
-                res
             }
-            .join(", "),
-            answer
-        );
+            Fallback::Collapse => {
+                asking.retry(format!("{rejected}; translating without formatting"));
+                let plain = asking
+                    .ask(
+                        &job.llm.model,
+                        vec![system.clone(), user(job.user.replace(TOK_SEP, ""))],
+                    )
+                    .await?
+                    .replace(TOK_SEP, "");
+                let empty_segments = TOK_SEP.to_string().repeat(expected);
+                return Ok(asking.answer(format!("{plain}{empty_segments}"), Some(*fallback)));
+            }
+        }
+    }
+
+    let (index, task_type) = chunk;
+    Err(TrenError::SegmentMismatch {
+        index,
+        task_type,
+        expected: expected + 1,
+        found: count_markers(&answer) + 1,
+    })
+}
+
+use crate::chunk::open_document;
+
+/// Run document work, which calls pandoc and parses files, off the async
+/// workers the web server shares.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|err| TrenError::Io(std::io::Error::other(err)))?
+}
+
+/// Render a prompt template; a broken template is reported rather than panicking.
+fn render_prompt(template: &str, ctx: Value) -> Result<String> {
+    Environment::new()
+        .render_str(template, ctx)
+        .map_err(|err| TrenError::Config(format!("bad prompt template: {err}")))
+}
+
+/// Connection settings of an `LLM`. Unlike a bare `OpenAIConfig`, nothing is
+/// picked up from the environment behind its back.
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    openai: OpenAIConfig,
+    headers: HeaderMap,
+    query: Vec<(String, String)>,
+}
+
+impl Config for ProviderConfig {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.openai.headers();
+        // keyless local servers get no `Authorization: Bearer ` at all
+        if self.openai.api_key().expose_secret().is_empty() {
+            headers.remove(AUTHORIZATION);
+        }
+        headers.extend(self.headers.clone());
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        self.openai.url(path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        self.query
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    fn api_base(&self) -> &str {
+        self.openai.api_base()
+    }
+
+    fn api_key(&self) -> &SecretString {
+        self.openai.api_key()
+    }
+}
+
+fn llm_config(llm: &LLM) -> ProviderConfig {
+    let openai = OpenAIConfig::default()
+        .with_api_base(llm.url.clone())
+        .with_api_key(llm.api_key.clone().unwrap_or_default())
+        .with_org_id(llm.org_id.clone().unwrap_or_default())
+        .with_project_id(llm.project_id.clone().unwrap_or_default());
+    ProviderConfig {
+        openai,
+        headers: llm.headers.clone(),
+        query: llm.query.clone(),
+    }
+}
 
-        return Ok(answer);
+/// HTTP client for the provider, with its request timeout.
+fn http_client(llm: &LLM) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = llm.timeout {
+        builder = builder.timeout(timeout);
     }
+    builder
+        .build()
+        .map_err(|err| TrenError::Config(format!("cannot set up the HTTP client: {err}")))
 }
 
-use crate::chunk::{AST, pandoc_ast::PandocAST};
+pub fn llm_client(llm: &LLM) -> Result<Client<ProviderConfig>> {
+    Ok(Client::with_config(llm_config(llm)).with_http_client(http_client(llm)?))
+}
 
-pub async fn process_job(job: &Job) -> Result<()> {
-    let mut ast = PandocAST::default();
-    ast.import(&job.input)?;
+/// Translate a job. Chunks in `previous` with the same source text are reused
+/// instead of sent to the LLM, so a paused or interrupted job continues where
+/// it stopped even if the document gained or lost chunks; among records of
+/// the same text, the one nearest the chunk's index is taken. Outstanding
+/// requests are dropped once `cancel` fires.
+pub async fn process_job(
+    job: &Job,
+    events: &UnboundedSender<Event>,
+    cancel: &CancellationToken,
+    previous: &[Record],
+) -> Result<()> {
+    let (input, output, document) = (job.input.clone(), job.output.clone(), job.document.clone());
+    let ast = blocking(move || open_document(&input, &output, &document)).await?;
 
     let micps = ast.to_mipcs();
+    let total = micps.main.len() + micps.sides.len();
+    let done = AtomicUsize::new(0);
+    events.send(Event::Started { total }).ok();
 
-    let client = Client::<OpenAIConfig>::with_config(
-        OpenAIConfig::default().with_api_base(job.llm.url.clone()),
-    );
+    let mut by_source: HashMap<(TaskType, &str), Vec<&Record>> = HashMap::new();
+    for record in previous {
+        by_source
+            .entry((record.task_type.clone(), record.source.as_str()))
+            .or_default()
+            .push(record);
+    }
 
-    let special_tokens = vec!["𐑣"];
+    let http = http_client(&job.llm)?;
+    let config = llm_config(&job.llm);
 
-    let task_stream = async |src: Vec<String>, task_type: TaskType| -> Result<Vec<String>> {
+    let special_tokens = vec![TOK_SEP.to_string()];
+    let system = render_prompt(
+        &job.system,
+        context!(
+            source_language => job.src,
+            target_language => job.tar,
+            special_tokens => special_tokens),
+    )?;
+
+    let task_stream = async |src: Vec<String>, task_type: TaskType| -> Vec<Result<Record>> {
         let parallel = job.parallel;
         let mut processings = stream::iter(src.clone())
             .enumerate()
             .map(|(i, mipc)| {
-                let client = client.clone();
+                let client = (&http, &config);
                 let back_chunks = match task_type {
                     TaskType::Main => 32,
                     TaskType::Side => 0,
                 };
-                let previous_chunks = &src[i.saturating_sub(back_chunks)..i];
+                let previous_chunks = match micps.context.get(&(task_type.clone(), i)) {
+                    Some(context) => context.as_slice(),
+                    None => &src[i.saturating_sub(back_chunks)..i],
+                };
+                let next_chunks = micps
+                    .next
+                    .get(&(task_type.clone(), i))
+                    .map_or(&[][..], Vec::as_slice);
                 let mut new_args = job.clone();
-                new_args.system = render!(&job.system,
-                    source_language => job.src,
-                    target_language => job.tar,
-                    special_tokens => special_tokens);
-                new_args.user = render!(&job.user,
-                    previous_chunks => previous_chunks,
-                    source_text => mipc);
-                async move { (i, chat(&client, new_args.clone(), mipc).await) }
+                new_args.system = system.clone();
+                let user = render_prompt(
+                    &job.user,
+                    context!(
+                        previous_chunks => previous_chunks,
+                        next_chunks => next_chunks,
+                        source_text => mipc),
+                );
+                let task_type = task_type.clone();
+                let reused = by_source
+                    .get(&(task_type.clone(), mipc.as_str()))
+                    .and_then(|records| {
+                        records.iter().min_by_key(|record| record.index.abs_diff(i))
+                    })
+                    .map(|record| Record {
+                        index: i,
+                        ..(*record).clone()
+                    });
+                async move {
+                    if let Some(record) = reused {
+                        return (i, Ok(record));
+                    }
+                    new_args.user = match user {
+                        Ok(user) => user,
+                        Err(err) => return (i, Err(err)),
+                    };
+                    let answer = chat(
+                        client,
+                        new_args,
+                        mipc.clone(),
+                        (i, task_type.clone()),
+                        events,
+                    )
+                    .await;
+                    let record = answer.map(|answer| Record {
+                        index: i,
+                        task_type,
+                        source: mipc,
+                        target: answer.text,
+                        attempts: answer.attempts,
+                        fallback: answer.fallback,
+                        prompt_tokens: answer.prompt_tokens,
+                        completion_tokens: answer.completion_tokens,
+                    });
+                    (i, record)
+                }
             })
             .buffer_unordered(parallel)
+            .inspect(|(_, record)| {
+                if let Ok(record) = record {
+                    events
+                        .send(Event::Chunk {
+                            record: record.clone(),
+                            done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                            total,
+                        })
+                        .ok();
+                }
+            })
+            .take_until(cancel.cancelled())
             .collect::<Vec<_>>()
             .await;
         processings.sort_by_key(|item| item.0);
-        processings
-            .into_iter()
-            .map(|item| item.1)
-            .collect::<Result<Vec<_>>>()
+        processings.into_iter().map(|item| item.1).collect()
     };
 
-    let result = Tasks {
-        main: task_stream.clone()(micps.main.into(), TaskType::Main)
-            .await?
-            .into(),
-        sides: task_stream.clone()(micps.sides.into(), TaskType::Side)
-            .await?
-            .into(),
+    let mut records = task_stream(micps.main.into(), TaskType::Main).await;
+    if !cancel.is_cancelled() {
+        records.extend(task_stream(micps.sides.into(), TaskType::Side).await);
+    }
+
+    // keep whatever is translated, even if the job stops here
+    write_sheet(
+        &job.inter_sheet,
+        records.iter().filter_map(|record| record.as_ref().ok()),
+    )?;
+    if cancel.is_cancelled() {
+        return Err(TrenError::Cancelled);
+    }
+
+    let records = records.into_iter().collect::<Result<Vec<_>>>()?;
+    let targets = |task_type: TaskType| {
+        records
+            .iter()
+            .filter(|record| record.task_type == task_type)
+            .map(|record| record.target.clone())
+            .collect()
     };
+    let result = Tasks::with_chunks(targets(TaskType::Main), targets(TaskType::Side));
 
-    ast.apply_mipcs(result)?;
+    let output = job.output.clone();
+    blocking(move || {
+        let mut ast = ast;
+        ast.apply_mipcs(result)?;
+        ast.export(&output)
+    })
+    .await?;
 
-    ast.export(&job.output)?;
+    events.send(Event::Finished).ok();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::DocumentArgs;
+    use axum::{Json, Router, http::StatusCode, response::IntoResponse, routing::post};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    /// Serve `router` on a free local port; gives its base URL.
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        url
+    }
+
+    /// A chat completion answering `text`.
+    fn completion(text: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": "stub",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "stub",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": "stop",
+            }],
+        })
+    }
+
+    /// A provider answering `answer(model, last message)`; keeps the model
+    /// and last message of every request.
+    async fn answering(
+        answer: fn(&str, &str) -> String,
+    ) -> (String, Arc<Mutex<Vec<(String, String)>>>) {
+        let asked = Arc::new(Mutex::new(vec![]));
+        let log = asked.clone();
+        let router = Router::new().route(
+            "/chat/completions",
+            post(move |Json(request): Json<serde_json::Value>| async move {
+                let model = request["model"].as_str().unwrap().to_string();
+                let text = request["messages"].as_array().unwrap().last().unwrap()["content"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                let answer = answer(&model, &text);
+                log.lock().unwrap().push((model, text));
+                Json(completion(&answer))
+            }),
+        );
+        (serve(router).await, asked)
+    }
+
+    /// A provider answering the `i`th request with `statuses[i]` and every
+    /// later one with "ok"; gives how many requests it got.
+    async fn scripted(statuses: Vec<(StatusCode, &'static str)>) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        let router = Router::new().route(
+            "/chat/completions",
+            post(move || async move {
+                match statuses.get(count.fetch_add(1, Ordering::Relaxed)) {
+                    Some(&(status, body)) => (status, body.to_string()).into_response(),
+                    None => Json(completion("ok")).into_response(),
+                }
+            }),
+        );
+        (serve(router).await, requests)
+    }
+
+    fn job(url: &str, input: PathBuf) -> Job {
+        Job {
+            src: "English".to_string(),
+            tar: "French".to_string(),
+            inter_sheet: input.with_extension("inter.csv"),
+            output: input.with_extension("out.csv"),
+            input,
+            document: DocumentArgs::default(),
+            llm: LLM {
+                url: url.to_string(),
+                api_key: None,
+                org_id: None,
+                project_id: None,
+                headers: HeaderMap::new(),
+                query: vec![],
+                timeout: None,
+                model: "stub".to_string(),
+            },
+            system: "Translate.".to_string(),
+            user: "{{ source_text }}".to_string(),
+            parallel: 1,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    fn record(index: usize, source: &str, target: &str) -> Record {
+        Record {
+            index,
+            task_type: TaskType::Main,
+            source: source.to_string(),
+            target: target.to_string(),
+            attempts: 1,
+            fallback: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resuming_reuses_the_rows_whose_source_is_unchanged() {
+        let (url, asked) = answering(|_, text| text.to_uppercase()).await;
+        let dir = std::env::temp_dir().join(format!("tren-{}", uuid::Uuid::now_v7()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.csv");
+        std::fs::write(&input, "Hello\nWorld\nNew text\n").unwrap();
+        let job = job(&url, input);
+        // a sheet from an earlier run of a different version of the document
+        let previous = [
+            record(0, "Hello", "Bonjour"),
+            record(1, "Old world", "Vieux monde"),
+            record(7, "Gone", "Parti"),
+        ];
+
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+        process_job(&job, &events, &CancellationToken::new(), &previous)
+            .await
+            .unwrap();
+        let output = std::fs::read_to_string(&job.output).unwrap();
+        let sheet = crate::sheet::read_sheet(&job.inter_sheet).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let asked = asked.lock().unwrap();
+        assert_eq!(
+            asked.iter().map(|(_, text)| text).collect::<Vec<_>>(),
+            ["World", "New text"]
+        );
+        assert_eq!(output, "Bonjour\nWORLD\nNEW TEXT\n");
+        assert_eq!(
+            sheet.iter().map(|r| r.target.as_str()).collect::<Vec<_>>(),
+            ["Bonjour", "WORLD", "NEW TEXT"]
+        );
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (*name, value.parse().unwrap()))
+            .map(|(name, value)| (reqwest::header::HeaderName::from_static(name), value))
+            .collect()
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_a_date() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "3")])),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            retry_after(&headers(&[
+                ("retry-after-ms", "1500"),
+                ("retry-after", "3")
+            ])),
+            Some(Duration::from_millis(1500))
+        );
+        let at = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        let wait = retry_after(&headers(&[("retry-after", &at)])).unwrap();
+        assert!(wait > Duration::from_secs(28) && wait <= Duration::from_secs(30));
+        // a date already past or a value that is neither means no hint
+        let past = (chrono::Utc::now() - chrono::Duration::seconds(30)).to_rfc2822();
+        assert_eq!(retry_after(&headers(&[("retry-after", &past)])), None);
+        assert_eq!(retry_after(&headers(&[("retry-after", "soon")])), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            retry_delay: 1.0,
+            max_retry_delay: 10.0,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            for (failures, full) in [(1, 1.0), (2, 2.0), (3, 4.0), (4, 8.0), (5, 10.0), (9, 10.0)] {
+                let delay = backoff(&policy, failures).as_secs_f64();
+                assert!(
+                    (full / 2.0..=full).contains(&delay),
+                    "{delay}s after {failures} failures"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn only_transient_statuses_are_retried() {
+        let transient = [
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::REQUEST_TIMEOUT,
+            StatusCode::TOO_MANY_REQUESTS,
+        ];
+        let fatal = [
+            (StatusCode::BAD_REQUEST, "bad request"),
+            (StatusCode::UNAUTHORIZED, "no key"),
+            (StatusCode::NOT_FOUND, "no such model"),
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"error":{"code":"insufficient_quota"}}"#,
+            ),
+        ];
+        let statuses = transient
+            .iter()
+            .map(|&status| (status, "try later"))
+            .chain(fatal)
+            .collect();
+        let (url, _) = scripted(statuses).await;
+        let job = job(&url, PathBuf::new());
+        let (http, config) = (http_client(&job.llm).unwrap(), llm_config(&job.llm));
+        let request = CreateChatCompletionRequestArgs::default()
+            .model("stub")
+            .messages(vec![ChatCompletionRequestUserMessage::from("Hello").into()])
+            .build()
+            .unwrap();
+        for status in transient {
+            let result = send_chat(&http, &config, &request).await;
+            assert!(
+                matches!(result, Err(Failure::Transient { .. })),
+                "{status} is transient"
+            );
+        }
+        for (status, _) in fatal {
+            let result = send_chat(&http, &config, &request).await;
+            assert!(
+                matches!(result, Err(Failure::Fatal(TrenError::Provider(_)))),
+                "{status} is fatal"
+            );
+        }
+        assert!(send_chat(&http, &config, &request).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn the_retry_after_hint_comes_with_the_failure() {
+        let router = Router::new().route(
+            "/chat/completions",
+            post(|| async {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [("retry-after", "7")],
+                    "slow down",
+                )
+            }),
+        );
+        let job = job(&serve(router).await, PathBuf::new());
+        let request = CreateChatCompletionRequestArgs::default()
+            .model("stub")
+            .messages(vec![ChatCompletionRequestUserMessage::from("Hello").into()])
+            .build()
+            .unwrap();
+        let result = send_chat(
+            &http_client(&job.llm).unwrap(),
+            &llm_config(&job.llm),
+            &request,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(Failure::Transient { retry_after: Some(wait), .. }) if wait == Duration::from_secs(7)
+        ));
+    }
+
+    /// Ask for `payload` under `retry`, as the job would.
+    async fn ask(url: &str, retry: RetryPolicy, payload: &str) -> Result<Answer> {
+        let mut job = job(url, PathBuf::new());
+        job.user = payload.to_string();
+        job.retry = retry;
+        let (http, config) = (http_client(&job.llm)?, llm_config(&job.llm));
+        let (events, _) = tokio::sync::mpsc::unbounded_channel();
+        chat(
+            (&http, &config),
+            job,
+            payload.to_string(),
+            (0, TaskType::Main),
+            &events,
+        )
+        .await
+    }
+
+    fn transport_retries(transport_retries: u8) -> RetryPolicy {
+        RetryPolicy {
+            transport_retries,
+            retry_delay: 0.001,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_up_to_the_limit() {
+        let failing = vec![(StatusCode::SERVICE_UNAVAILABLE, "busy"); 2];
+        let (url, requests) = scripted(failing.clone()).await;
+        let answer = ask(&url, transport_retries(2), "Hello").await.unwrap();
+        assert_eq!((answer.text.as_str(), answer.attempts), ("ok", 3));
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+        let (url, requests) = scripted(failing).await;
+        let Err(TrenError::Provider(err)) = ask(&url, transport_retries(1), "Hello").await else {
+            panic!("the second failure is one too many");
+        };
+        assert!(err.contains("giving up at attempt 2"), "{err}");
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn fatal_failures_are_not_retried() {
+        let (url, requests) = scripted(vec![(StatusCode::UNAUTHORIZED, "no key")]).await;
+        assert!(matches!(
+            ask(&url, transport_retries(5), "Hello").await,
+            Err(TrenError::Provider(_))
+        ));
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+    }
+
+    /// What a request of the fallback chain was for, from its last message.
+    fn kind(text: &str) -> &'static str {
+        if text.starts_with("Your translation has") {
+            "correction"
+        } else if text.starts_with("Here is a text") {
+            "segment"
+        } else if text.contains(TOK_SEP) {
+            "translation"
+        } else {
+            "collapsed"
+        }
+    }
+
+    fn asked(asked: &Mutex<Vec<(String, String)>>) -> Vec<(String, &'static str)> {
+        asked
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(model, text)| (model.clone(), kind(text)))
+            .collect()
+    }
+
+    fn fallbacks(fallbacks: &[Fallback]) -> RetryPolicy {
+        RetryPolicy {
+            validation_retries: 1,
+            fallbacks: fallbacks.to_vec(),
+            fallback_model: Some("big".to_string()),
+            ..Default::default()
+        }
+    }
+
+    const ALL: [Fallback; 4] = [
+        Fallback::Corrective,
+        Fallback::Model,
+        Fallback::Segments,
+        Fallback::Collapse,
+    ];
+
+    #[tokio::test]
+    async fn fallbacks_run_in_order_until_one_keeps_the_markers() {
+        // only a request without markers to keep can be answered
+        let (url, requests) = answering(|_, text| match kind(text) {
+            "segment" => String::new(),
+            _ => "Bonjour monde".to_string(),
+        })
+        .await;
+        let answer = ask(&url, fallbacks(&ALL), "Hello 𐑙world").await.unwrap();
+        assert_eq!(answer.text, "Bonjour monde𐑙");
+        assert_eq!(answer.fallback, Some(Fallback::Collapse));
+        assert_eq!(answer.attempts, 5);
+        let stub = || "stub".to_string();
+        assert_eq!(
+            asked(&requests),
+            [
+                (stub(), "translation"),
+                (stub(), "correction"),
+                ("big".to_string(), "correction"),
+                (stub(), "segment"),
+                (stub(), "collapsed"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn each_fallback_can_give_the_answer() {
+        // the fallback model gets the markers right
+        let (url, requests) = answering(|model, _| match model {
+            "big" => "Bonjour 𐑙monde".to_string(),
+            _ => "Bonjour monde".to_string(),
+        })
+        .await;
+        let answer = ask(&url, fallbacks(&ALL), "Hello 𐑙world").await.unwrap();
+        assert_eq!(answer.fallback, Some(Fallback::Model));
+        assert_eq!(asked(&requests).len(), 3);
+
+        // each segment is translated on its own and joined back
+        let (url, _) = answering(|_, text| match text.rsplit("\n\n").next() {
+            Some("Hello") if kind(text) == "segment" => "Bonjour".to_string(),
+            Some("world") if kind(text) == "segment" => "monde".to_string(),
+            _ => "Bonjour monde".to_string(),
+        })
+        .await;
+        let answer = ask(&url, fallbacks(&ALL), "Hello 𐑙world").await.unwrap();
+        assert_eq!(answer.fallback, Some(Fallback::Segments));
+        assert_eq!(answer.text, "Bonjour 𐑙monde");
+    }
+
+    #[tokio::test]
+    async fn a_mismatch_is_an_error_once_every_fallback_fails() {
+        let (url, requests) = answering(|_, text| match kind(text) {
+            "segment" => String::new(),
+            _ => "Bonjour monde".to_string(),
+        })
+        .await;
+        let policy = fallbacks(&[Fallback::Corrective, Fallback::Model, Fallback::Segments]);
+        assert!(matches!(
+            ask(&url, policy, "Hello 𐑙world").await,
+            Err(TrenError::SegmentMismatch {
+                expected: 2,
+                found: 1,
+                ..
+            })
+        ));
+        assert_eq!(asked(&requests).len(), 4);
+    }
+}
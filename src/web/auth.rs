@@ -0,0 +1,246 @@
+use crate::cli::{UserCLIArgs, UserCommand};
+use crate::web::jobs::JobInfo;
+use crate::web::{AppError, AppState};
+use anyhow::{Result, anyhow, bail};
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+};
+use axum::{
+    Extension, Json,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const USERS_FILE: &str = "users.json";
+const SESSION_COOKIE: &str = "tren_session";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct User {
+    name: String,
+    password_hash: String,
+}
+
+/// The user a request belongs to; `None` when authentication is disabled.
+#[derive(Debug, Clone)]
+pub struct CurrentUser(pub Option<String>);
+
+impl CurrentUser {
+    pub fn can_access(&self, info: &JobInfo) -> bool {
+        self.0.is_none() || self.0 == info.owner
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Auth {
+    enabled: bool,
+    users: Arc<Mutex<HashMap<String, User>>>,
+    sessions: Arc<Mutex<HashMap<String, String>>>,
+}
+
+fn load_users(data: &Path) -> Result<HashMap<String, User>> {
+    let path = data.join(USERS_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let users = serde_json::from_slice::<Vec<User>>(&std::fs::read(path)?)?;
+    Ok(users.into_iter().map(|u| (u.name.clone(), u)).collect())
+}
+
+fn save_users(data: &Path, users: &HashMap<String, User>) -> Result<()> {
+    let mut users = users.values().collect::<Vec<_>>();
+    users.sort_by_key(|u| &u.name);
+    std::fs::create_dir_all(data)?;
+    std::fs::write(data.join(USERS_FILE), serde_json::to_vec_pretty(&users)?)?;
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|e| anyhow!(e))?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!(e))?
+        .to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// Manage local accounts stored in `<DATA>/users.json`.
+pub fn manage_users(user_cli: UserCLIArgs) -> Result<()> {
+    let mut users = load_users(&user_cli.data)?;
+    match user_cli.command {
+        UserCommand::Add { name, password } => {
+            if users.contains_key(&name) {
+                bail!("user {name} already exists");
+            }
+            let password = match password {
+                Some(password) => password,
+                None => rpassword::prompt_password(format!("Password for {name}: "))?,
+            };
+            if password.is_empty() {
+                bail!("password must not be empty");
+            }
+            let password_hash = hash_password(&password)?;
+            users.insert(
+                name.clone(),
+                User {
+                    name,
+                    password_hash,
+                },
+            );
+        }
+        UserCommand::Remove { name } => {
+            users
+                .remove(&name)
+                .ok_or(anyhow!("user {name} does not exist"))?;
+        }
+        UserCommand::List => {
+            let mut names = users.keys().collect::<Vec<_>>();
+            names.sort();
+            names.iter().for_each(|name| println!("{name}"));
+            return Ok(());
+        }
+    }
+    save_users(&user_cli.data, &users)
+}
+
+impl Auth {
+    pub fn new(enabled: bool, data: &Path) -> Result<Self> {
+        let users = load_users(data)?;
+        if enabled && users.is_empty() {
+            bail!(
+                "authentication is enabled but there is no user; add one with `tren user --data {} add <NAME>`",
+                data.display()
+            );
+        }
+        Ok(Auth {
+            enabled,
+            users: Arc::new(Mutex::new(users)),
+            sessions: Arc::default(),
+        })
+    }
+
+    fn session_user(&self, headers: &HeaderMap) -> Option<String> {
+        let token = session_token(headers)?;
+        self.sessions.lock().unwrap().get(&token).cloned()
+    }
+}
+
+/// Session token from either `Authorization: Bearer` or the session cookie.
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer.map(|t| t.trim().to_string()).or_else(|| {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(k, _)| *k == SESSION_COOKIE)
+            .map(|(_, v)| v.to_string())
+    })
+}
+
+/// Resolve the current user; reject the request if authentication is on and
+/// there is no valid session.
+pub async fn require_user(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let user = if state.auth.enabled {
+        let name = state
+            .auth
+            .session_user(request.headers())
+            .ok_or(AppError(StatusCode::UNAUTHORIZED, anyhow!("not logged in")))?;
+        CurrentUser(Some(name))
+    } else {
+        CurrentUser(None)
+    };
+    request.extensions_mut().insert(user);
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize)]
+pub struct Login {
+    name: String,
+    password: String,
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    Json(login): Json<Login>,
+) -> Result<impl IntoResponse, AppError> {
+    let hash = state
+        .auth
+        .users
+        .lock()
+        .unwrap()
+        .get(&login.name)
+        .map(|user| user.password_hash.clone());
+    // hashing is slow on purpose; keep it off the async workers and the lock
+    let password = login.password;
+    let valid = tokio::task::spawn_blocking(move || {
+        hash.is_some_and(|hash| verify_password(&password, &hash))
+    })
+    .await?;
+    if !valid {
+        return Err(AppError(
+            StatusCode::UNAUTHORIZED,
+            anyhow!("wrong user name or password"),
+        ));
+    }
+
+    let token = rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    state
+        .auth
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(token.clone(), login.name.clone());
+
+    Ok((
+        [(
+            header::SET_COOKIE,
+            format!("{SESSION_COOKIE}={token}; Path=/; HttpOnly; SameSite=Strict"),
+        )],
+        Json(serde_json::json!({ "name": login.name, "token": token })),
+    ))
+}
+
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(token) = session_token(&headers) {
+        state.auth.sessions.lock().unwrap().remove(&token);
+    }
+    (
+        [(
+            header::SET_COOKIE,
+            format!("{SESSION_COOKIE}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0"),
+        )],
+        StatusCode::NO_CONTENT,
+    )
+}
+
+pub async fn me(Extension(user): Extension<CurrentUser>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "name": user.0 }))
+}
@@ -0,0 +1,474 @@
+use crate::chunk::spreadsheet::Selection;
+use crate::cli::{
+    DEFAULT_META_KEYS, DEFAULT_SYSTEM_PROMPT, DEFAULT_USER_PROMPT, DocumentArgs, Fallback, Job,
+    LLM, RetryPolicy, inter_sheet_fallback, output_fallback,
+};
+use crate::sheet::apply_sheet;
+use crate::web::auth::CurrentUser;
+use crate::web::{AppError, AppState, bad_request, conflict, not_found};
+use axum::{
+    Extension, Json,
+    extract::{Multipart, Path, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Waiting,
+    Processing,
+    Paused,
+    Successed,
+    Failed,
+    Cancelled,
+}
+
+/// A job as seen by the web UI; mirrors `jobSchema` in `webui/src/lib/job.ts`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub source_lang: String,
+    pub target_lang: String,
+    pub model: String,
+    pub system_prompt: String,
+    pub user_prompt: String,
+    pub input_file: String,
+    pub created_at: DateTime<Utc>,
+    pub status: Status,
+    /// Name of the translated document; `null` until the job succeeds.
+    pub output_file: Option<String>,
+    /// User who submitted the job when authentication is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Why the job failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Spreadsheet cells to translate and given as context, as with
+    /// `--sheets`, `--columns`, `--range` and `--context-columns`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sheets: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub columns: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub context_columns: Vec<String>,
+    /// How the document is read and written, as with `--from`, `--to`,
+    /// `--meta`, `--comments`, `--max-line-chars` and `--max-lines`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default = "default_meta_keys")]
+    pub meta_keys: Vec<String>,
+    #[serde(default)]
+    pub comments: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_line_chars: Option<NonZeroUsize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lines: Option<NonZeroUsize>,
+    /// Chunks sent to the LLM at once, as with `--parallel` of `tren run`.
+    #[serde(default = "default_parallel")]
+    pub parallel: NonZeroUsize,
+}
+
+fn default_meta_keys() -> Vec<String> {
+    DEFAULT_META_KEYS.map(String::from).to_vec()
+}
+
+fn default_parallel() -> NonZeroUsize {
+    NonZeroUsize::MIN
+}
+
+/// Subdirectory of the job directory holding the input, the intermediate sheet
+/// and the output, so uploaded names never clash with the job's own files.
+const FILES_DIR: &str = "files";
+
+impl JobInfo {
+    /// Map onto the CLI job; every file lives inside `job_dir`.
+    pub fn to_job(&self, job_dir: &std::path::Path, llm: &LLM) -> Job {
+        let input = job_dir.join(FILES_DIR).join(&self.input_file);
+        Job {
+            src: self.source_lang.clone(),
+            tar: self.target_lang.clone(),
+            inter_sheet: inter_sheet_fallback(&None, &input),
+            output: output_fallback(&None, &input),
+            document: DocumentArgs {
+                from: self.from.clone(),
+                to: self.to.clone(),
+                meta_keys: self.meta_keys.clone(),
+                comments: self.comments,
+                sheets: self.sheets.clone(),
+                columns: self.columns.clone(),
+                ranges: self.ranges.clone(),
+                context_columns: self.context_columns.clone(),
+                max_line_chars: self.max_line_chars,
+                max_lines: self.max_lines,
+            },
+            llm: LLM {
+                model: self.model.clone(),
+                ..llm.clone()
+            },
+            system: self.system_prompt.clone(),
+            user: self.user_prompt.clone(),
+            input,
+            parallel: self.parallel.get(),
+            retry: self.retry.clone(),
+        }
+    }
+}
+
+fn field_error(err: axum::extract::multipart::MultipartError) -> AppError {
+    // a body over the size limit is a 413, not a malformed request
+    AppError(err.status(), err.into())
+}
+
+/// Parse an optional field, falling back to `default` when absent.
+fn parse_field<T: std::str::FromStr>(value: Option<String>, default: T) -> Result<T, AppError> {
+    Ok(optional_field(value)?.unwrap_or(default))
+}
+
+/// Parse an optional field that is left unset when absent.
+fn optional_field<T: std::str::FromStr>(value: Option<String>) -> Result<Option<T>, AppError> {
+    value
+        .map(|v| {
+            v.trim()
+                .parse()
+                .map_err(|_| bad_request(&format!("not a valid value: {v}")))
+        })
+        .transpose()
+}
+
+/// Split an optional comma-separated field.
+fn list_field(value: Option<String>) -> Vec<String> {
+    value.map_or(vec![], |v| {
+        v.split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    })
+}
+
+pub async fn create_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let id = Uuid::now_v7();
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut upload = None;
+    while let Some(field) = multipart.next_field().await.map_err(field_error)? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "input_file" {
+            // only keep the base name; the extension tells pandoc the format
+            let file_name = field
+                .file_name()
+                .and_then(|f| std::path::Path::new(f).file_name())
+                .and_then(|f| f.to_str())
+                .ok_or(bad_request("input_file has no file name"))?
+                .to_string();
+            upload = Some((file_name, field.bytes().await.map_err(field_error)?));
+        } else {
+            fields.insert(name, field.text().await.map_err(field_error)?);
+        }
+    }
+
+    let mut take = |key: &str| fields.remove(key).filter(|v| !v.trim().is_empty());
+    let defaults = RetryPolicy::default();
+    let retry = RetryPolicy {
+        transport_retries: parse_field(take("transport_retries"), defaults.transport_retries)?,
+        validation_retries: parse_field(take("validation_retries"), defaults.validation_retries)?,
+        retry_delay: parse_field(take("retry_delay"), defaults.retry_delay)?,
+        max_retry_delay: parse_field(take("max_retry_delay"), defaults.max_retry_delay)?,
+        fallbacks: match take("fallbacks") {
+            Some(list) => list
+                .split(',')
+                .map(|f| Fallback::from_str(f.trim(), true))
+                .collect::<Result<_, _>>()
+                .map_err(|e| bad_request(&format!("bad fallbacks: {e}")))?,
+            None => defaults.fallbacks,
+        },
+        fallback_model: take("fallback_model"),
+    };
+    let (input_file, bytes) = upload.ok_or(bad_request("missing input_file"))?;
+    let created_at = Utc::now();
+    let info = JobInfo {
+        id,
+        name: take("name").unwrap_or(format!("{:x}", created_at.timestamp())),
+        source_lang: take("source_lang").ok_or(bad_request("missing source_lang"))?,
+        target_lang: take("target_lang").ok_or(bad_request("missing target_lang"))?,
+        model: take("model").ok_or(bad_request("missing model"))?,
+        system_prompt: take("system_prompt").unwrap_or(DEFAULT_SYSTEM_PROMPT.to_string()),
+        user_prompt: take("user_prompt").unwrap_or(DEFAULT_USER_PROMPT.to_string()),
+        input_file,
+        created_at,
+        status: Status::Waiting,
+        output_file: None,
+        owner: user.0,
+        error: None,
+        retry,
+        sheets: list_field(take("sheets")),
+        columns: list_field(take("columns")),
+        ranges: list_field(take("ranges")),
+        context_columns: list_field(take("context_columns")),
+        from: take("from"),
+        to: take("to"),
+        meta_keys: take("meta_keys").map_or(default_meta_keys(), |keys| list_field(Some(keys))),
+        comments: parse_field(take("comments"), false)?,
+        max_line_chars: optional_field(take("max_line_chars"))?,
+        max_lines: optional_field(take("max_lines"))?,
+        parallel: parse_field(take("parallel"), default_parallel())?,
+    };
+    Selection::new(&info.to_job(&state.job_dir(&id), &state.llm).document)
+        .map_err(|err| bad_request(&err.to_string()))?;
+
+    // only a valid request gets a job directory
+    let files_dir = state.job_dir(&id).join(FILES_DIR);
+    tokio::fs::create_dir_all(&files_dir).await?;
+    tokio::fs::write(files_dir.join(&info.input_file), bytes).await?;
+    state.submit(info.clone()).await?;
+
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn list_jobs(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+) -> Json<Vec<JobInfo>> {
+    let mut jobs = state
+        .jobs
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|info| user.can_access(info))
+        .cloned()
+        .collect::<Vec<_>>();
+    jobs.sort_by_key(|info| std::cmp::Reverse(info.created_at));
+    Json(jobs)
+}
+
+pub async fn get_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<JobInfo>, AppError> {
+    Ok(Json(state.get_for(&id, &user)?))
+}
+
+pub async fn get_output(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let info = state.get_for(&id, &user)?;
+    let file_name = info
+        .output_file
+        .ok_or(conflict(format!("job {id} has no output yet")))?;
+
+    attachment(&state.job_dir(&id).join(FILES_DIR).join(file_name)).await
+}
+
+/// Download the intermediate sheet with every chunk translated so far.
+pub async fn get_sheet(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let info = state.get_for(&id, &user)?;
+    let sheet = info.to_job(&state.job_dir(&id), &state.llm).inter_sheet;
+    if !sheet.exists() {
+        return Err(conflict(format!("job {id} has no intermediate sheet yet")));
+    }
+    attachment(&sheet).await
+}
+
+async fn attachment(path: &std::path::Path) -> Result<impl IntoResponse + use<>, AppError> {
+    let bytes = tokio::fs::read(path).await?;
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}\""),
+            ),
+        ],
+        bytes,
+    ))
+}
+
+/// Cancel a job that has not finished yet; delete a job that has.
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    let info = state.get_for(&id, &user)?;
+    if matches!(
+        info.status,
+        Status::Successed | Status::Failed | Status::Cancelled
+    ) {
+        if state.running.lock().unwrap().contains_key(&id) {
+            return Err(conflict(format!("job {id} is still stopping")));
+        }
+        state.jobs.lock().unwrap().remove(&id);
+        state.saving.lock().unwrap().remove(&id);
+        tokio::fs::remove_dir_all(state.job_dir(&id)).await?;
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    let info = state
+        .update(&id, |info| {
+            if matches!(
+                info.status,
+                Status::Waiting | Status::Processing | Status::Paused
+            ) {
+                info.status = Status::Cancelled;
+            }
+        })
+        .await?
+        .ok_or(not_found(&id))?;
+    state.stop(&id);
+    Ok(Json(info).into_response())
+}
+
+/// Stop a job but keep its translated chunks so it can be resumed.
+pub async fn pause_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<JobInfo>, AppError> {
+    state.get_for(&id, &user)?;
+    let info = state
+        .update(&id, |info| {
+            if matches!(info.status, Status::Waiting | Status::Processing) {
+                info.status = Status::Paused;
+            }
+        })
+        .await?
+        .ok_or(not_found(&id))?;
+    if info.status != Status::Paused {
+        return Err(conflict(format!("job {id} cannot be paused")));
+    }
+    state.stop(&id);
+    Ok(Json(info))
+}
+
+/// Put a paused job back in the queue.
+pub async fn resume_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<JobInfo>, AppError> {
+    let info = state.get_for(&id, &user)?;
+    if info.status != Status::Paused {
+        return Err(conflict(format!("job {id} is not paused")));
+    }
+    if state.running.lock().unwrap().contains_key(&id) {
+        return Err(conflict(format!("job {id} is still stopping")));
+    }
+    let info = state
+        .update(&id, |info| {
+            if info.status == Status::Paused {
+                info.status = Status::Waiting;
+            }
+        })
+        .await?
+        .ok_or(not_found(&id))?;
+    state.queue.send(id)?;
+    Ok(Json(info))
+}
+
+/// Rebuild the output from the intermediate sheet without the LLM; an edited
+/// sheet can be uploaded as the `sheet` field first.
+pub async fn apply_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+    mut multipart: Multipart,
+) -> Result<Json<JobInfo>, AppError> {
+    let info = state.get_for(&id, &user)?;
+    if matches!(info.status, Status::Waiting | Status::Processing) {
+        return Err(conflict(format!("job {id} is not finished yet")));
+    }
+    if state.running.lock().unwrap().contains_key(&id) {
+        return Err(conflict(format!("job {id} is still stopping")));
+    }
+
+    let job = info.to_job(&state.job_dir(&id), &state.llm);
+    // an uploaded sheet only replaces the current one once it applies cleanly
+    let uploaded = job.inter_sheet.with_extension("upload.csv");
+    let mut sheet = job.inter_sheet.clone();
+    while let Some(field) = multipart.next_field().await.map_err(field_error)? {
+        if field.name() == Some("sheet") {
+            let bytes = field.bytes().await.map_err(field_error)?;
+            tokio::fs::write(&uploaded, bytes).await?;
+            sheet = uploaded.clone();
+        }
+    }
+    if !sheet.exists() {
+        return Err(conflict(format!("job {id} has no intermediate sheet")));
+    }
+
+    let (input, output, applied) = (job.input.clone(), job.output.clone(), sheet.clone());
+    let document = job.document.clone();
+    let result =
+        tokio::task::spawn_blocking(move || apply_sheet(&input, &applied, &output, &document))
+            .await?;
+    if sheet == uploaded {
+        match result {
+            Ok(()) => tokio::fs::rename(&uploaded, &job.inter_sheet).await?,
+            Err(_) => tokio::fs::remove_file(&uploaded).await?,
+        }
+    }
+    result.map_err(|e| AppError(StatusCode::UNPROCESSABLE_ENTITY, e.into()))?;
+
+    let info = state
+        .update(&id, |info| {
+            info.status = Status::Successed;
+            info.error = None;
+            info.output_file = job
+                .output
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string());
+        })
+        .await?
+        .ok_or(not_found(&id))?;
+    Ok(Json(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::tests::{job_info, state};
+
+    #[test]
+    fn job_options_reach_the_translation() {
+        let (state, _queue) = state(vec![]);
+        let info = JobInfo {
+            from: Some("markdown".to_string()),
+            comments: true,
+            max_line_chars: NonZeroUsize::new(42),
+            parallel: NonZeroUsize::new(4).unwrap(),
+            ..job_info(Status::Waiting)
+        };
+        let job = info.to_job(&state.job_dir(&info.id), &state.llm);
+        assert_eq!(job.parallel, 4);
+        assert_eq!(job.document.from.as_deref(), Some("markdown"));
+        assert!(job.document.comments);
+        assert_eq!(job.document.max_line_chars, NonZeroUsize::new(42));
+    }
+}
@@ -0,0 +1,346 @@
+use crate::cli::{LLM, WebCLIArgs};
+use crate::models::{Model, list_models, load_catalogue};
+use anyhow::{Result, anyhow};
+use axum::{
+    Json, Router,
+    extract::{DefaultBodyLimit, State},
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+pub mod auth;
+mod jobs;
+mod progress;
+mod queue;
+mod webui;
+
+use auth::{Auth, CurrentUser};
+use jobs::JobInfo;
+use progress::Progress;
+
+#[derive(Clone)]
+pub struct AppState {
+    data: PathBuf,
+    jobs: Arc<Mutex<HashMap<Uuid, JobInfo>>>,
+    queue: mpsc::UnboundedSender<Uuid>,
+    auth: Auth,
+    progress: Progress,
+    /// Directory to serve the web UI from instead of the embedded one.
+    webui: Option<PathBuf>,
+    /// Admin-curated model catalogue.
+    catalogue: Arc<Vec<Model>>,
+    /// Cancellation handles of the jobs being processed.
+    running: Arc<Mutex<HashMap<Uuid, CancellationToken>>>,
+    /// Held by each update of a job until it is saved, so the last change
+    /// made is also the last one written.
+    saving: Arc<Mutex<HashMap<Uuid, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Provider settings shared by every job; only the model differs.
+    llm: LLM,
+}
+
+impl AppState {
+    fn job_dir(&self, id: &Uuid) -> PathBuf {
+        self.data.join("jobs").join(id.to_string())
+    }
+
+    /// A job the user is allowed to see; other users' jobs look nonexistent.
+    fn get_for(&self, id: &Uuid, user: &CurrentUser) -> Result<JobInfo, AppError> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|info| user.can_access(info))
+            .cloned()
+            .ok_or(not_found(id))
+    }
+
+    /// Modify a job in memory and persist it; returns the updated job.
+    async fn update(&self, id: &Uuid, f: impl FnOnce(&mut JobInfo)) -> Result<Option<JobInfo>> {
+        let saving = self.saving.lock().unwrap().entry(*id).or_default().clone();
+        let _saving = saving.lock().await;
+        let info = self.jobs.lock().unwrap().get_mut(id).map(|info| {
+            f(info);
+            info.clone()
+        });
+        if let Some(ref info) = info {
+            queue::save(&self.job_dir(id), info).await?;
+        }
+        Ok(info)
+    }
+
+    /// Stop a job if it is being processed.
+    fn stop(&self, id: &Uuid) {
+        if let Some(cancel) = self.running.lock().unwrap().get(id) {
+            cancel.cancel();
+        }
+    }
+
+    /// Register a new job, persist it and put it at the back of the queue.
+    async fn submit(&self, info: JobInfo) -> Result<()> {
+        queue::save(&self.job_dir(&info.id), &info).await?;
+        self.jobs.lock().unwrap().insert(info.id, info.clone());
+        self.queue.send(info.id)?;
+        Ok(())
+    }
+}
+
+pub struct AppError(StatusCode, anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for AppError {
+    fn from(err: E) -> Self {
+        AppError(StatusCode::INTERNAL_SERVER_ERROR, err.into())
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (
+            self.0,
+            Json(serde_json::json!({ "error": self.1.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+fn not_found(id: &Uuid) -> AppError {
+    AppError(StatusCode::NOT_FOUND, anyhow!("job {id} not found"))
+}
+
+fn bad_request(msg: &str) -> AppError {
+    AppError(StatusCode::BAD_REQUEST, anyhow!(msg.to_string()))
+}
+
+fn conflict(msg: String) -> AppError {
+    AppError(StatusCode::CONFLICT, anyhow!(msg))
+}
+
+async fn models(State(state): State<AppState>) -> Result<Json<Vec<Model>>, AppError> {
+    Ok(Json(list_models(&state.llm, &state.catalogue).await?))
+}
+
+pub async fn serve(web_cli: WebCLIArgs) -> Result<()> {
+    let auth = Auth::new(web_cli.auth, &web_cli.data)?;
+    let llm = LLM::new(&web_cli.llm, String::new())?;
+    let catalogue_path = web_cli
+        .catalogue
+        .clone()
+        .unwrap_or(web_cli.data.join("models.json"));
+    let catalogue = if web_cli.catalogue.is_some() || catalogue_path.exists() {
+        load_catalogue(&catalogue_path)?
+    } else {
+        vec![]
+    };
+    let jobs_dir = web_cli.data.join("jobs");
+    tokio::fs::create_dir_all(&jobs_dir).await?;
+    let jobs = queue::load(&jobs_dir).await?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut pending = jobs
+        .values()
+        .filter(|info| info.status == jobs::Status::Waiting)
+        .map(|info| info.id)
+        .collect::<Vec<_>>();
+    // UUIDv7 sorts by creation time
+    pending.sort();
+    for id in pending {
+        tx.send(id)?;
+    }
+
+    let state = AppState {
+        data: web_cli.data,
+        jobs: Arc::new(Mutex::new(jobs)),
+        queue: tx,
+        auth,
+        progress: Progress::default(),
+        running: Arc::default(),
+        saving: Arc::default(),
+        webui: web_cli.webui,
+        catalogue: Arc::new(catalogue),
+        llm,
+    };
+    queue::spawn_workers(state.clone(), rx, web_cli.parallel);
+
+    let listener = TcpListener::bind((web_cli.host, web_cli.port)).await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(state, web_cli.max_upload)).await?;
+
+    Ok(())
+}
+
+/// Every route of the server; request bodies are capped at `max_upload` MB.
+fn router(state: AppState, max_upload: usize) -> Router {
+    let api = Router::new()
+        .route("/api/me", get(auth::me))
+        .route("/api/models", get(models))
+        .route("/api/jobs", get(jobs::list_jobs).post(jobs::create_job))
+        .route(
+            "/api/jobs/{id}",
+            get(jobs::get_job).delete(jobs::cancel_job),
+        )
+        .route("/api/jobs/{id}/pause", post(jobs::pause_job))
+        .route("/api/jobs/{id}/resume", post(jobs::resume_job))
+        .route("/api/jobs/{id}/output", get(jobs::get_output))
+        .route("/api/jobs/{id}/sheet", get(jobs::get_sheet))
+        .route("/api/jobs/{id}/apply", post(jobs::apply_job))
+        .route("/api/jobs/{id}/events", get(progress::job_events))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_user,
+        ));
+    Router::new()
+        .route("/api/login", post(auth::login))
+        .route("/api/logout", post(auth::logout))
+        .merge(api)
+        .fallback(webui::serve_webui)
+        .layer(DefaultBodyLimit::max(max_upload * 1024 * 1024))
+        .with_state(state)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::cli::RetryPolicy;
+    use jobs::Status;
+
+    pub fn job_info(status: Status) -> JobInfo {
+        JobInfo {
+            id: Uuid::now_v7(),
+            name: "test".to_string(),
+            source_lang: "en".to_string(),
+            target_lang: "fr".to_string(),
+            model: "test".to_string(),
+            system_prompt: String::new(),
+            user_prompt: String::new(),
+            input_file: "input.md".to_string(),
+            created_at: chrono::Utc::now(),
+            status,
+            output_file: None,
+            owner: None,
+            error: None,
+            retry: RetryPolicy::default(),
+            sheets: vec![],
+            columns: vec![],
+            ranges: vec![],
+            context_columns: vec![],
+            from: None,
+            to: None,
+            meta_keys: vec![],
+            comments: false,
+            max_line_chars: None,
+            max_lines: None,
+            parallel: std::num::NonZeroUsize::MIN,
+        }
+    }
+
+    /// A server state over a fresh data directory holding `jobs`; the queue
+    /// receiver is given back so that submitting works.
+    pub fn state(jobs: Vec<JobInfo>) -> (AppState, mpsc::UnboundedReceiver<Uuid>) {
+        let data = std::env::temp_dir().join(format!("tren-{}", Uuid::now_v7()));
+        for info in &jobs {
+            std::fs::create_dir_all(data.join("jobs").join(info.id.to_string())).unwrap();
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let state = AppState {
+            data,
+            jobs: Arc::new(Mutex::new(
+                jobs.into_iter().map(|info| (info.id, info)).collect(),
+            )),
+            queue: tx,
+            auth: Auth::default(),
+            progress: Progress::default(),
+            webui: None,
+            catalogue: Arc::default(),
+            running: Arc::default(),
+            saving: Arc::default(),
+            llm: LLM {
+                url: "http://127.0.0.1:9".to_string(),
+                api_key: None,
+                org_id: None,
+                project_id: None,
+                headers: Default::default(),
+                query: vec![],
+                timeout: None,
+                model: String::new(),
+            },
+        };
+        (state, rx)
+    }
+
+    #[tokio::test]
+    async fn the_last_update_of_a_job_is_the_one_saved() {
+        let info = job_info(Status::Waiting);
+        let id = info.id;
+        let (state, _queue) = state(vec![info]);
+        let updates = (0..32).map(|i| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                state
+                    .update(&id, |info| info.name = i.to_string())
+                    .await
+                    .unwrap()
+            })
+        });
+        futures::future::join_all(updates).await;
+        let saved: JobInfo =
+            serde_json::from_slice(&std::fs::read(state.job_dir(&id).join("job.json")).unwrap())
+                .unwrap();
+        assert_eq!(saved.name, state.jobs.lock().unwrap()[&id].name);
+        std::fs::remove_dir_all(&state.data).unwrap();
+    }
+
+    const BOUNDARY: &str = "tren-boundary";
+
+    /// A `multipart/form-data` body of text fields and one `input_file`.
+    fn multipart(fields: &[(&str, &str)], file_name: &str, file: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        for (name, value) in fields {
+            body.extend(
+                format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n")
+                    .as_bytes(),
+            );
+        }
+        body.extend(
+            format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"input_file\"; filename=\"{file_name}\"\r\n\r\n")
+                .as_bytes(),
+        );
+        body.extend(file);
+        body.extend(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+        body
+    }
+
+    #[tokio::test]
+    async fn uploads_are_limited_by_max_upload() {
+        let (state, _queue) = state(vec![]);
+        let data = state.data.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/jobs", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router(state, 4)).await });
+
+        let fields = [("source_lang", "en"), ("target_lang", "fr"), ("model", "m")];
+        let upload = |size: usize| {
+            reqwest::Client::new()
+                .post(&url)
+                .header(
+                    "content-type",
+                    format!("multipart/form-data; boundary={BOUNDARY}"),
+                )
+                .body(multipart(&fields, "big.md", &vec![b'a'; size]))
+                .send()
+        };
+        // over axum's default of 2 MB but within --max-upload
+        let accepted = upload(3 * 1024 * 1024).await.unwrap();
+        assert_eq!(accepted.status(), StatusCode::CREATED);
+        let rejected = upload(5 * 1024 * 1024).await.unwrap();
+        assert_eq!(rejected.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        std::fs::remove_dir_all(data).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use crate::translate::Event;
+use crate::web::auth::CurrentUser;
+use crate::web::jobs::JobInfo;
+use crate::web::{AppError, AppState};
+use axum::{
+    Extension,
+    extract::{Path, State},
+    response::sse::{self, KeepAlive, Sse},
+};
+use futures::{Stream, StreamExt, stream};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+struct Live {
+    sender: broadcast::Sender<Event>,
+    /// Every event so far, so late subscribers can rebuild the preview.
+    history: Vec<Event>,
+}
+
+/// Progress of the jobs currently being processed.
+#[derive(Clone, Default)]
+pub struct Progress(Arc<Mutex<HashMap<Uuid, Live>>>);
+
+impl Progress {
+    /// Start tracking a job; the job is untracked once the returned sender
+    /// and all its clones are dropped.
+    pub fn track(&self, id: Uuid) -> mpsc::UnboundedSender<Event> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+        let (sender, _) = broadcast::channel(256);
+        self.0.lock().unwrap().insert(
+            id,
+            Live {
+                sender,
+                history: vec![],
+            },
+        );
+
+        let progress = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if let Some(live) = progress.0.lock().unwrap().get_mut(&id) {
+                    live.history.push(event.clone());
+                    live.sender.send(event).ok();
+                }
+            }
+            progress.0.lock().unwrap().remove(&id);
+        });
+        tx
+    }
+
+    fn subscribe(&self, id: &Uuid) -> Option<(Vec<Event>, broadcast::Receiver<Event>)> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|live| (live.history.clone(), live.sender.subscribe()))
+    }
+}
+
+fn job_event(info: &JobInfo) -> sse::Event {
+    sse::Event::default()
+        .event("job")
+        .json_data(info)
+        .unwrap_or_default()
+}
+
+fn progress_event(event: &Event) -> sse::Event {
+    sse::Event::default()
+        .event("progress")
+        .json_data(event)
+        .unwrap_or_default()
+}
+
+/// Stream a job over Server-Sent Events: a `job` event with the current job,
+/// every `progress` event so far and then live ones, and a final `job` event
+/// once processing stops.
+pub async fn job_events(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<Uuid>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, AppError> {
+    let info = state.get_for(&id, &user)?;
+    let (history, receiver) = match state.progress.subscribe(&id) {
+        Some((history, receiver)) => (history, Some(receiver)),
+        None => (vec![], None),
+    };
+
+    let was_live = receiver.is_some();
+    let head = std::iter::once(job_event(&info))
+        .chain(history.iter().map(progress_event))
+        .collect::<Vec<_>>();
+    let live = stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((progress_event(&event), Some(receiver))),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let tail = stream::iter(was_live.then_some(())).then(move |()| {
+        let (state, user) = (state.clone(), user.clone());
+        async move {
+            state
+                .get_for(&id, &user)
+                .map(|info| job_event(&info))
+                .unwrap_or_default()
+        }
+    });
+
+    Ok(Sse::new(stream::iter(head).chain(live).chain(tail).map(Ok))
+        .keep_alive(KeepAlive::default()))
+}
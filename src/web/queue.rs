@@ -0,0 +1,202 @@
+use crate::translate::{Event, Record, process_job};
+use crate::web::AppState;
+use crate::web::jobs::{JobInfo, Status};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Semaphore, mpsc};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+const JOB_FILE: &str = "job.json";
+const CHUNKS_FILE: &str = "chunks.jsonl";
+
+/// Persist the job metadata next to its files; written to a temporary file
+/// first so a crash never leaves a half-written `job.json`.
+pub async fn save(job_dir: &Path, info: &JobInfo) -> Result<()> {
+    let tmp = job_dir.join(format!("{JOB_FILE}.tmp"));
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(info)?).await?;
+    tokio::fs::rename(&tmp, job_dir.join(JOB_FILE)).await?;
+    Ok(())
+}
+
+/// Read every `jobs/<id>/job.json` under the data directory. Jobs that were
+/// waiting or interrupted while processing are put back to `waiting`.
+pub async fn load(jobs_dir: &Path) -> Result<HashMap<Uuid, JobInfo>> {
+    let mut jobs = HashMap::new();
+    let mut entries = tokio::fs::read_dir(jobs_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path().join(JOB_FILE);
+        let Ok(bytes) = tokio::fs::read(&path).await else {
+            continue;
+        };
+        let mut info = match serde_json::from_slice::<JobInfo>(&bytes) {
+            Ok(info) => info,
+            Err(err) => {
+                eprintln!("skipping {}: {err}", path.display());
+                continue;
+            }
+        };
+        if info.status == Status::Processing {
+            info.status = Status::Waiting;
+            save(&entry.path(), &info).await?;
+        }
+        jobs.insert(info.id, info);
+    }
+    Ok(jobs)
+}
+
+/// Chunks translated by earlier runs of the job, from `chunks.jsonl`.
+async fn load_chunks(job_dir: &Path) -> Vec<Record> {
+    let Ok(journal) = tokio::fs::read_to_string(job_dir.join(CHUNKS_FILE)).await else {
+        return vec![];
+    };
+    // a chunk can be journaled more than once; the latest wins
+    journal
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+        .map(|record| ((record.task_type.clone(), record.index), record))
+        .collect::<HashMap<_, _>>()
+        .into_values()
+        .collect()
+}
+
+/// Mark a waiting job as processing and register its cancellation handle in
+/// the same step, so a cancel or pause that sees it processing always stops
+/// it; `None` if it was paused or cancelled while waiting in the queue.
+async fn start(state: &AppState, id: Uuid) -> Result<Option<(JobInfo, CancellationToken)>> {
+    let cancel = CancellationToken::new();
+    let mut started = false;
+    let info = state
+        .update(&id, |info| {
+            if info.status == Status::Waiting {
+                info.status = Status::Processing;
+                state.running.lock().unwrap().insert(id, cancel.clone());
+                started = true;
+            }
+        })
+        .await
+        .inspect_err(|_| {
+            state.running.lock().unwrap().remove(&id);
+        })?;
+    Ok(info.filter(|_| started).map(|info| (info, cancel)))
+}
+
+async fn run(state: &AppState, id: Uuid) -> Result<()> {
+    let Some((info, cancel)) = start(state, id).await? else {
+        return Ok(());
+    };
+    let progress = state.progress.track(id);
+    let result = translate(state, &info, &cancel, progress.clone()).await;
+    state.running.lock().unwrap().remove(&id);
+
+    state
+        .update(&id, |info| match result {
+            Ok(output_file) => {
+                info.status = Status::Successed;
+                info.error = None;
+                info.output_file = output_file;
+            }
+            // paused or cancelled on request
+            Err(_) if cancel.is_cancelled() => (),
+            Err(ref err) => {
+                eprintln!("job {id} failed: {err:#}");
+                info.status = Status::Failed;
+                info.error = Some(format!("{err:#}"));
+            }
+        })
+        .await?;
+    // keep the job tracked until its final status is saved
+    drop(progress);
+    Ok(())
+}
+
+/// Translate a job marked as processing, journaling every chunk so it can be
+/// resumed later; gives the name of the output file.
+async fn translate(
+    state: &AppState,
+    info: &JobInfo,
+    cancel: &CancellationToken,
+    progress: mpsc::UnboundedSender<Event>,
+) -> Result<Option<String>> {
+    let job_dir = state.job_dir(&info.id);
+    let job = info.to_job(&job_dir, &state.llm);
+    let previous = load_chunks(&job_dir).await;
+
+    let (events, mut rx) = mpsc::unbounded_channel::<Event>();
+    let mut journal = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(job_dir.join(CHUNKS_FILE))
+        .await?;
+    let journaling = tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Event::Chunk { ref record, .. } = event {
+                let mut line = serde_json::to_vec(record)?;
+                line.push(b'\n');
+                journal.write_all(&line).await?;
+            }
+            progress.send(event).ok();
+        }
+        journal.flush().await?;
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let result = process_job(&job, &events, cancel, &previous).await;
+    drop(events);
+    journaling.await??;
+    result?;
+    Ok(job
+        .output
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string()))
+}
+
+/// Take queued job ids in order and process at most `parallel` at a time.
+pub fn spawn_workers(state: AppState, mut queue: mpsc::UnboundedReceiver<Uuid>, parallel: usize) {
+    let permits = Arc::new(Semaphore::new(parallel.max(1)));
+    tokio::spawn(async move {
+        while let Some(id) = queue.recv().await {
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                break;
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = run(&state, id).await {
+                    eprintln!("job {id}: {err:#}");
+                }
+                drop(permit);
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::auth::CurrentUser;
+    use crate::web::jobs::cancel_job;
+    use crate::web::tests::{job_info, state};
+    use axum::Extension;
+    use axum::extract::{Path, State};
+
+    #[tokio::test]
+    async fn cancelling_a_starting_job_stops_it() {
+        let info = job_info(Status::Waiting);
+        let id = info.id;
+        let (state, _queue) = state(vec![info]);
+        let (_, cancel) = start(&state, id).await.unwrap().unwrap();
+        // the cancel lands before the job reaches process_job
+        let cancelled =
+            cancel_job(State(state.clone()), Extension(CurrentUser(None)), Path(id)).await;
+        assert!(cancelled.is_ok());
+        assert!(cancel.is_cancelled());
+        assert_eq!(state.jobs.lock().unwrap()[&id].status, Status::Cancelled);
+        // nor is it started again from the queue
+        state.running.lock().unwrap().remove(&id);
+        assert!(start(&state, id).await.unwrap().is_none());
+        std::fs::remove_dir_all(&state.data).unwrap();
+    }
+}
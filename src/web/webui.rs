@@ -0,0 +1,59 @@
+use crate::web::AppState;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{StatusCode, Uri, header},
+    response::{IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+use std::path::{Component, Path};
+
+/// Static build of `webui/` (`bun run build`); empty if it was not built.
+#[derive(RustEmbed)]
+#[folder = "webui/build"]
+#[allow_missing = true]
+struct Assets;
+
+async fn read_asset(dir: Option<&Path>, path: &str) -> Option<Vec<u8>> {
+    match dir {
+        Some(dir) => {
+            let path = Path::new(path);
+            // never leave the web UI directory
+            if path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+            {
+                return None;
+            }
+            tokio::fs::read(dir.join(path)).await.ok()
+        }
+        None => Assets::get(path).map(|file| file.data.into_owned()),
+    }
+}
+
+/// Serve the web UI; unknown paths fall back to `index.html` so client-side
+/// routes work on reload.
+pub async fn serve_webui(State(state): State<AppState>, uri: Uri) -> Response {
+    let dir = state.webui.as_deref();
+    let path = uri.path().trim_start_matches('/');
+    if path.starts_with("api/") {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let (path, data) = match read_asset(dir, path).await {
+        Some(data) if !path.is_empty() => (path, data),
+        _ => match read_asset(dir, "index.html").await {
+            Some(data) => ("index.html", data),
+            None => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    "web UI is not built; run `bun run build` in webui/ or pass --webui",
+                )
+                    .into_response();
+            }
+        },
+    };
+
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    ([(header::CONTENT_TYPE, mime.to_string())], Body::from(data)).into_response()
+}
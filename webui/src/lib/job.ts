@@ -9,11 +9,19 @@ export const default_system_prompt = `You are an expert translator. Please trans
 - Do not give any alternative translation or including any previous context, notes or discussion.`
 
 export const default_user_prompt = `{%- set previous_chunks = previous_chunks[-8:] -%}
+{%- if previous_chunks or next_chunks -%}
 {%- if previous_chunks -%}
 Given the previous context:
 
 {{ previous_chunks | join(\\"\\n\\n\\") }}
 
+{% endif -%}
+{%- if next_chunks -%}
+Given the next context:
+
+{{ next_chunks | join(\\"\\n\\n\\") }}
+
+{% endif -%}
 Only translate the following text:
 
 {% endif -%}
@@ -23,8 +31,10 @@ const modelId = z.string().nonempty()
 
 export const modelSchema = z.object({
 	id: modelId,
-	name: z.string().max(32),
+	name: z.string(),
 	params: z.string(),
+	open: z.boolean().nullish(),
+	context_window: z.number().int().nullish(),
 })
 
 export type Model = z.infer<typeof modelSchema>;
@@ -49,9 +59,11 @@ export const jobCreateSchema = z.object({
 const jobSchema = z.object({
 	...jobCreateSchema.shape,
 	id: z.uuidv7(),
-	created_at: z.date(),
-	status: z.enum(["waiting", "processing", "successed", "failed"]),
-	output_file: z.file().mime(acceptedTypes).optional()
+	input_file: z.string(),
+	created_at: z.coerce.date(),
+	status: z.enum(["waiting", "processing", "paused", "successed", "failed", "cancelled"]),
+	output_file: z.string().nullable(),
+	error: z.string().optional()
 })
 	.transform(job => ({
 		...job,